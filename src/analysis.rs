@@ -1,285 +1,910 @@
-use crate::parser::{self, Tree};
-use core::panic;
-use miette::{Context, Diagnostic, Result, SourceSpan};
-use std::{collections::HashMap, rc::Rc, sync::Arc};
+use crate::lower;
+use crate::parser::{self, Ast, NodeId, Tree};
+use crate::source::Source;
+use crate::trace::{self, Tracer};
+use crate::visit::{self, Visitor};
+use miette::{Diagnostic, Result, SourceSpan};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    rc::Rc,
+    sync::Arc,
+};
+
+/// Finds the declaration every variable reference means and records, in
+/// [`Resolution::locals`], where the interpreter will find it at runtime.
+///
+/// The scopes mirror the environments the interpreter makes: one for the
+/// globals, one for each block run as a statement and one for each call, which
+/// holds the parameters and the declarations at the top of the body. A local
+/// is then `depth` environments out from the reference, at the `slot` it was
+/// given when declared. Globals are left out of the table and looked up by
+/// name, so a function may use one the file only declares further down.
 #[derive(Debug)]
 pub struct Resolver {
     pub ast: parser::Parser,
-    pub current_id: usize,
-    //Var scopes
+    //The parsed program, empty until `resolve` runs
+    pub tree: Rc<Ast>,
+    //Var scopes, the globals first
     pub vscope: Vec<Scope>,
     //TODO: maybe add this
     //Funcitons as declared in scope
     pub fscope: Vec<FunctionInfo>,
-    //Funcitons calls
-    pub func_table: HashMap<String, usize>,
+    //Funcitons calls, by the name node that declares the function
+    pub func_table: HashMap<NodeId, usize>,
     //Funcitons as declared in scope
     pub cscope: Vec<ClassInfo>,
     //Class calls
     pub class_table: HashMap<String, usize>,
+    //Warn when a variable declared without a value is read but never assigned
+    pub warn_unassigned: bool,
+    //What the node being resolved is inside of, for `return`, `this` and `super`
+    pub function_kind: FunctionKind,
+    pub class_kind: ClassKind,
+    //Warn when a declaration shadows one of an enclosing scope
+    pub warn_shadow: bool,
+    //Warn about locals never read, parameters never used and top level functions never called
+    pub warn_unused: bool,
+    pub warnings: Vec<miette::Report>,
+    //How many loops enclose the node being resolved, within the current function
+    pub loop_depth: usize,
+    //Set by `--trace=resolve`
+    pub trace: Option<Tracer>,
+    //What is resolved so far, moved into the resolution at the end
+    pub locals: HashMap<NodeId, Local>,
+    //What the file each import loaded resolved to, set by the loader
+    pub imports: HashMap<NodeId, Rc<Resolution>>,
+    //What each function captures so far, moved into the resolution at the end
+    pub captures: HashMap<NodeId, Vec<Local>>,
+    //The functions around the node being resolved, the innermost last, with the index of their scope
+    pub functions: Vec<(NodeId, usize)>,
+    //The names the file declares at its top level, see `globals`
+    global_names: HashSet<String>,
+    //Globals read by a function before they are declared
+    late_reads: HashSet<String>,
+    //Names an assignment somewhere in the file writes to, see `Assignments`,
+    //and globals declared more than once
+    assigned: HashSet<String>,
+}
+
+/// Where a local variable lives at runtime, `depth` environments out from the
+/// one its reference runs in, at index `slot` of that environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Local {
+    pub depth: u32,
+    pub slot: u32,
+}
+
+/// What the resolver learned about one file, kept next to its [`Ast`] and keyed
+/// by [`NodeId`] the way the spans are. A program that skipped the resolver,
+/// such as one read from a `.sexpr` file, runs with an empty one and looks
+/// every variable up by name.
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// Where each local variable a declaration or reference names lives.
+    /// Globals have no entry.
+    pub locals: HashMap<NodeId, Local>,
+    /// The locals of enclosing scopes that each `Fun` or `Lambda` node reads or
+    /// assigns, in the order they are first used, with `depth` counted from the
+    /// environment the function is declared in. A function without an entry
+    /// was not resolved and keeps every enclosing environment.
+    pub captures: HashMap<NodeId, Vec<Local>>,
+    /// The resolution of the file each top level `import` loaded, next to
+    /// [`Ast::imports`].
+    pub imports: HashMap<NodeId, Rc<Resolution>>,
 }
 
 #[derive(Debug, Diagnostic)]
 #[diagnostic(help("this is a semantics/resolution error"))]
 pub struct ResolverError {
     #[source_code]
-    source: Arc<String>,
+    source: Arc<Source>,
     #[label("main issue")]
     primary_span: SourceSpan,
+    message: String,
 }
 
 impl std::error::Error for ResolverError {}
 impl std::fmt::Display for ResolverError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "")
+        write!(f, "{}", self.message)
+    }
+}
+
+#[derive(Debug, Diagnostic)]
+#[diagnostic(help("give one of them another name, or assign to the first one"))]
+pub struct RedeclaredError {
+    #[source_code]
+    source: Arc<Source>,
+    #[label("declared again here")]
+    primary_span: SourceSpan,
+    #[label("first declared here")]
+    previous_span: SourceSpan,
+    name: String,
+}
+
+impl std::error::Error for RedeclaredError {}
+impl std::fmt::Display for RedeclaredError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` is already declared in this scope", self.name)
+    }
+}
+
+#[derive(Debug, Diagnostic)]
+#[diagnostic(
+    severity(Warning),
+    help("the outer one can not be used in this scope after this")
+)]
+pub struct ShadowWarning {
+    #[source_code]
+    source: Arc<Source>,
+    #[label("this declaration")]
+    primary_span: SourceSpan,
+    #[label("shadows this one")]
+    previous_span: SourceSpan,
+    name: String,
+}
+
+impl std::error::Error for ShadowWarning {}
+impl std::fmt::Display for ShadowWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "`{}` shadows an outer declaration", self.name)
+    }
+}
+
+#[derive(Debug, Diagnostic)]
+#[diagnostic(
+    severity(Warning),
+    help("if this is on purpose, start the name with `_` to silence this")
+)]
+pub struct UnusedWarning {
+    #[source_code]
+    source: Arc<Source>,
+    #[label("declared here")]
+    primary_span: SourceSpan,
+    name: String,
+    kind: Binding,
+}
+
+impl std::error::Error for UnusedWarning {}
+impl std::fmt::Display for UnusedWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = &self.name;
+        match self.kind {
+            Binding::Parameter => write!(f, "parameter `{name}` is never used"),
+            Binding::Function => write!(f, "function `{name}` is never called"),
+            Binding::Variable | Binding::Other => write!(f, "`{name}` is never read"),
+        }
+    }
+}
+
+#[derive(Debug, Diagnostic)]
+#[diagnostic(severity(Warning), help("this is a lint, the program still runs"))]
+pub struct ResolverWarning {
+    #[source_code]
+    source: Arc<Source>,
+    #[label("read here")]
+    primary_span: SourceSpan,
+    //The variable that is never assigned
+    name: String,
+}
+
+impl std::error::Error for ResolverWarning {}
+impl std::fmt::Display for ResolverWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{}` is read here but is never assigned, it is always nil",
+            self.name
+        )
     }
 }
 
 //TODO: Cont working on classes
 #[derive(Debug)]
 pub struct ClassInfo {
-    pub body: Rc<Tree>,
-    pub fields: Rc<Tree>,
-    pub methods: Rc<Tree>,
+    pub body: NodeId,
+    pub fields: NodeId,
+    pub methods: NodeId,
 }
 
 #[derive(Debug)]
 pub struct FunctionInfo {
-    pub body: Rc<Tree>,
+    pub body: NodeId,
     pub airity: usize,
 }
 
 #[derive(Debug)]
 pub struct Scope {
-    pub vlookup: HashMap<String, Rc<Tree>>,
+    //Maps a name to its declaration
+    pub vlookup: HashMap<String, Variable>,
+    //Variables declared without a value that nothing assigns yet, with the places they are read
+    pub unassigned: HashMap<String, Vec<Range<usize>>>,
 }
 
-pub trait Visitor {
-    fn visit_first(&mut self, var: &Tree) -> Result<(), miette::Report>;
-    fn visit_second(&mut self, var: &Tree) -> Result<(), miette::Report>;
+#[derive(Debug, Clone, Copy)]
+pub struct Variable {
+    //Where it lives in the environment of its scope, see `Local`
+    pub slot: u32,
+    //False while its initializer is resolved
+    pub defined: bool,
+    //The name node that declares it
+    pub decl: NodeId,
+    pub kind: Binding,
+    //Whether anything reads it, an assignment does not count
+    pub read: bool,
 }
-impl Visitor for Resolver {
-    fn visit_first(&mut self, var: &Tree) -> Result<(), miette::Report> {
-        match var {
-            Tree::Var(op, trees) => self.store_scope(op, trees)?,
-            Tree::NonTerm(op, trees) => if *op == parser::Op::Class {},
-            Tree::Fun {
-                name,
-                parameters,
-                body,
-            } => self.store_funcitons(name, parameters, body)?,
-            _ => {}
-        }
-        self.visit_second(var)?;
-        Ok(())
-    }
 
-    fn visit_second(&mut self, var: &Tree) -> Result<(), miette::Report> {
-        match var {
-            Tree::Nil => todo!(),
-            Tree::ExprStatment(trees) => {
-                self.resolve_expr_stmnt(trees)?;
-            }
-            Tree::Var(op, val) => {
-                self.vscope.reverse();
-                println!("WE FIND: {}", op);
-                for i in &self.vscope {
-                    if i.vlookup.contains_key(op) {
-                        {
-                            println!("variable with name of: {op}, maps to : {i:?} ");
-                            return Ok(());
-                        }
-                    }
+/// The innermost function around a node, `return` is only allowed in one and
+/// `init` may only return without a value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FunctionKind {
+    None,
+    Function,
+    Method,
+    Initializer,
+}
+
+/// The innermost class around a node, `this` needs one and `super` one that
+/// inherits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClassKind {
+    None,
+    Class,
+    Subclass,
+}
+
+/// What declared a name, which decides the unused warning it gets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Variable,
+    Parameter,
+    Function,
+    //Classes and the names an import declares, never warned about
+    Other,
+}
+
+impl FunctionInfo {}
+
+// Everything a file defines at its top level, including what it imports itself,
+// since all files share one global scope when they run.
+pub fn globals(ast: &Ast) -> Vec<String> {
+    let mut output = Vec::new();
+    for id in &ast.program {
+        match &ast[*id] {
+            Tree::Var(name, _) | Tree::Fun { name, .. } => output.push(ast.text(*name).to_string()),
+            Tree::NonTerm(parser::Op::Import, _) => {
+                if let Some(module) = ast.imports.get(id) {
+                    output.extend(globals(module));
                 }
             }
-            Tree::Call { callee, arguments } => {
-                self.resolve_fun(callee, arguments)?;
-            }
-            Tree::Atom(x) => {
-                self.resolve_atom(x)?;
-            }
-            Tree::NonTerm(op, trees) => {
-                self.resolve_non_term(op, trees)?;
-            }
-            Tree::Op(_) => {}
             _ => {}
         }
-        Ok(())
     }
+    output
 }
 
-impl FunctionInfo {}
+/// Collects the names that an assignment anywhere in a file writes to. A call
+/// through one of them may run any function by then, so its arity is only
+/// checked at runtime.
+#[derive(Debug, Default)]
+pub struct Assignments(pub HashSet<String>);
+
+impl Visitor for Assignments {
+    fn visit_non_term(&mut self, ast: &Ast, _id: NodeId, op: parser::Op, ids: &[NodeId]) {
+        if (op == parser::Op::Equal || op.is_compound_assignment())
+            && let [target, _] = ids
+            && let Tree::Atom(parser::Atom::Ident) = ast[*target]
+        {
+            self.0.insert(ast.text(*target).to_string());
+        }
+        visit::walk_ids(self, ast, ids)
+    }
+}
 
 impl Scope {
     pub fn new() -> Self {
         Self {
             vlookup: HashMap::new(),
+            unassigned: HashMap::new(),
         }
     }
 }
 impl Resolver {
     pub fn new(ast: parser::Parser) -> Self {
         Self {
+            tree: Rc::new(Ast::new(Arc::clone(&ast.input))),
             ast,
             vscope: vec![Scope::new()],
             fscope: vec![],
             func_table: HashMap::new(),
-            current_id: 0,
             cscope: vec![],
             class_table: HashMap::new(),
+            warn_unassigned: false,
+            warn_unused: false,
+            warn_shadow: false,
+            function_kind: FunctionKind::None,
+            class_kind: ClassKind::None,
+            warnings: vec![],
+            loop_depth: 0,
+            trace: None,
+            locals: HashMap::new(),
+            imports: HashMap::new(),
+            captures: HashMap::new(),
+            functions: Vec::new(),
+            global_names: HashSet::new(),
+            late_reads: HashSet::new(),
+            assigned: HashSet::new(),
+        }
+    }
+    // Runs `resolve` on `id`, between trace events when tracing.
+    fn traced(
+        &mut self,
+        id: NodeId,
+        resolve: impl FnOnce(&mut Self, NodeId) -> Result<(), miette::Report>,
+    ) -> Result<(), miette::Report> {
+        let Some(trace) = &mut self.trace else {
+            return resolve(self, id);
+        };
+        let ast = Rc::clone(&self.tree);
+        let what = trace::head(&ast, id);
+        trace.enter(&what, &ast.source, ast.span(id).start);
+        let output = resolve(self, id);
+        if let Some(trace) = &mut self.trace {
+            trace.exit(&what, output.as_ref().ok().map(|_| (&*ast, id)));
+        }
+        output
+    }
+    /// Resolves a node the interpreter executes, where a block has its own scope.
+    pub fn resolve_statement(&mut self, id: NodeId) -> Result<(), miette::Report> {
+        self.traced(id, Self::resolve_statement_inner)
+    }
+    fn resolve_statement_inner(&mut self, id: NodeId) -> Result<(), miette::Report> {
+        let ast = Rc::clone(&self.tree);
+        match &ast[id] {
+            Tree::Var(name, Some(value)) => {
+                self.declare(ast.text(*name), *name)?;
+                self.resolve_expr(*value)?;
+                self.define(ast.text(*name));
+                Ok(())
+            }
+            Tree::Var(name, None) => self.store_unassigned(ast.text(*name), *name),
+            Tree::Fun {
+                name,
+                parameters,
+                body,
+            } => {
+                // Declared before the body, so the function can call itself.
+                self.store_scope(ast.text(*name), *name, Binding::Function)?;
+                self.store_funcitons(*name, parameters, *body)?;
+                self.resolve_function(id, parameters, *body, FunctionKind::Function)
+            }
+            Tree::ExprStatment(trees) => self.resolve_exprs(trees),
+            Tree::NonTerm(op, trees) => self.resolve_non_term(id, op, trees),
+            _ => self.resolve_expr_inner(id),
         }
     }
+    /// Resolves a node the interpreter evaluates, where a group is only parentheses.
+    pub fn resolve_expr(&mut self, id: NodeId) -> Result<(), miette::Report> {
+        self.traced(id, Self::resolve_expr_inner)
+    }
+    fn resolve_expr_inner(&mut self, id: NodeId) -> Result<(), miette::Report> {
+        let ast = Rc::clone(&self.tree);
+        match &ast[id] {
+            Tree::Atom(parser::Atom::Ident) => self.resolve_atom(id),
+            Tree::Atom(parser::Atom::This) if self.class_kind == ClassKind::None => {
+                self.error("`this` can only be used inside a class", Some(id))
+            }
+            Tree::Atom(parser::Atom::Super) => match self.class_kind {
+                ClassKind::None => self.error("`super` can only be used inside a class", Some(id)),
+                ClassKind::Class => self.error(
+                    "`super` can not be used in a class without a superclass",
+                    Some(id),
+                ),
+                ClassKind::Subclass => Ok(()),
+            },
+            Tree::Nil | Tree::Atom(_) | Tree::Op(_) => Ok(()),
+            Tree::ExprStatment(trees) => self.resolve_exprs(trees),
+            Tree::Call { callee, arguments } => self.resolve_fun(*callee, arguments),
+            Tree::Lambda { parameters, body } => {
+                self.resolve_function(id, parameters, *body, FunctionKind::Function)
+            }
+            Tree::NonTerm(op, trees) => match (op, &trees[..]) {
+                (op, [target, value])
+                    if (*op == parser::Op::Equal || op.is_compound_assignment())
+                        && matches!(ast[*target], Tree::Atom(_)) =>
+                {
+                    self.resolve_assignment(*op, *target, *value)
+                }
+                // The name after a `.` is a field, not a variable.
+                (parser::Op::Field, [object, _]) => self.resolve_expr(*object),
+                _ => self.resolve_exprs(trees),
+            },
+            Tree::Var(..) | Tree::Fun { .. } => self.resolve_statement(id),
+        }
+    }
+    fn resolve_exprs(&mut self, trees: &[NodeId]) -> Result<(), miette::Report> {
+        for i in trees {
+            self.resolve_expr(*i)?;
+        }
+        Ok(())
+    }
     pub fn store_funcitons(
         &mut self,
-        name: &Rc<Tree>,
-        parameters: &Vec<Rc<Tree>>,
-        body: &Rc<Tree>,
+        name: NodeId,
+        parameters: &[NodeId],
+        body: NodeId,
     ) -> Result<(), miette::Report> {
         let val = FunctionInfo {
             airity: parameters.len(),
-            body: Rc::clone(body),
+            body,
         };
-        let out = match &**name {
-            Tree::Atom(atom) => atom,
-            _ => unreachable!(),
-        };
-        let name = match out {
-            parser::Atom::Ident { range, source } => {
-                let val = &(*source).as_str()[range.start..range.end];
-                String::from(val)
+        if self.ext_value(name).is_none() {
+            return self.error("you cant make this a variable name: ", Some(name));
+        }
+        self.func_table.insert(name, self.fscope.len());
+        self.fscope.push(val);
+        Ok(())
+    }
+    // Declares `input` in the innermost scope, a local gets the next free slot.
+    // A global may be declared again, a local only in an inner scope.
+    pub fn store_scope(
+        &mut self,
+        input: &str,
+        decl: NodeId,
+        kind: Binding,
+    ) -> Result<(), miette::Report> {
+        let global = self.vscope.len() == 1;
+        let (scope, outer) = self
+            .vscope
+            .split_last()
+            .expect("invariant borked: you cant have 0 scopes");
+        if let Some(x) = scope.vlookup.get(input).filter(|_| !global) {
+            return Err(RedeclaredError {
+                source: Arc::clone(&self.ast.input),
+                primary_span: self.source_span(decl),
+                previous_span: self.source_span(x.decl),
+                name: input.to_string(),
             }
-            x => return self.error("you cant make this a variable name: ", Some(x)),
+            .into());
+        }
+        let shadowed = outer.iter().rev().find_map(|x| x.vlookup.get(input));
+        if self.warn_shadow
+            && let Some(x) = shadowed
+        {
+            self.warnings.push(miette::Report::new(ShadowWarning {
+                source: Arc::clone(&self.ast.input),
+                primary_span: self.source_span(decl),
+                previous_span: self.source_span(x.decl),
+                name: input.to_string(),
+            }));
+        }
+        let scope = self
+            .vscope
+            .last_mut()
+            .expect("invariant borked: you cant have 0 scopes");
+        let slot = scope.vlookup.len() as u32;
+        let variable = Variable {
+            slot,
+            defined: true,
+            decl,
+            kind,
+            read: false,
         };
-        self.func_table.insert(name, self.current_id);
-        self.fscope.push(val);
+        scope.vlookup.insert(String::from(input), variable);
+        scope.unassigned.remove(input);
+        if !global {
+            self.locals.insert(decl, Local { depth: 0, slot });
+        }
         Ok(())
     }
-    pub fn store_scope(&mut self, input: &String, trees: &Rc<Tree>) -> Result<(), miette::Report> {
+    // Declares a variable whose initializer comes next, a local can not be read
+    // until `define` is called. A global can, it may have been declared before.
+    fn declare(&mut self, input: &str, decl: NodeId) -> Result<(), miette::Report> {
+        self.store_scope(input, decl, Binding::Variable)?;
+        if self.vscope.len() > 1 {
+            self.define_as(input, false);
+        }
+        Ok(())
+    }
+    fn define(&mut self, input: &str) {
+        self.define_as(input, true);
+    }
+    fn define_as(&mut self, input: &str, defined: bool) {
+        let scope = self
+            .vscope
+            .last_mut()
+            .expect("invariant borked: you cant have 0 scopes");
+        if let Some(x) = scope.vlookup.get_mut(input) {
+            x.defined = defined;
+        }
+    }
+    pub fn store_unassigned(&mut self, input: &str, decl: NodeId) -> Result<(), miette::Report> {
+        self.store_scope(input, decl, Binding::Variable)?;
         self.vscope
             .last_mut()
             .expect("invariant borked: you cant have 0 scopes")
-            .vlookup
-            .insert(String::from(input), Rc::clone(trees));
+            .unassigned
+            .insert(String::from(input), vec![]);
         Ok(())
     }
-    pub fn resolve_expr_stmnt(&mut self, trees: &Vec<Tree>) -> Result<(), miette::Report> {
-        self.vscope.push(Scope::new());
-        for i in trees {
-            self.visit_first(i)?;
+    pub fn pop_scope(&mut self) {
+        let scope = self
+            .vscope
+            .pop()
+            .expect("invariant borked: you cant have 0 scopes");
+        self.report_unassigned(scope.unassigned);
+        self.report_unused(scope.vlookup.into_values().collect());
+    }
+    fn report_unassigned(&mut self, unassigned: HashMap<String, Vec<Range<usize>>>) {
+        if !self.warn_unassigned {
+            return;
+        }
+        let mut reads: Vec<(String, Range<usize>)> = unassigned
+            .into_iter()
+            .flat_map(|(name, ranges)| ranges.into_iter().map(move |x| (name.clone(), x)))
+            .collect();
+        reads.sort_by_key(|(_, range)| range.start);
+        for (name, range) in reads {
+            self.warnings.push(miette::Report::new(ResolverWarning {
+                source: Arc::clone(&self.ast.input),
+                primary_span: SourceSpan::new(range.start.into(), range.end - range.start),
+                name,
+            }));
+        }
+    }
+    fn report_unused(&mut self, mut variables: Vec<Variable>) {
+        if !self.warn_unused {
+            return;
+        }
+        variables.sort_by_key(|x| self.tree.span(x.decl).start);
+        for x in variables {
+            let name = self.tree.text(x.decl);
+            if x.read || name.starts_with('_') || x.kind == Binding::Other {
+                continue;
+            }
+            self.warnings.push(miette::Report::new(UnusedWarning {
+                source: Arc::clone(&self.ast.input),
+                primary_span: self.source_span(x.decl),
+                name: name.to_string(),
+                kind: x.kind,
+            }));
         }
-        self.vscope.pop();
-        Ok(())
     }
-    pub fn resolve_atom(&mut self, x: &parser::Atom) -> Result<(), miette::Report> {
-        let output = match self.ext_value(x) {
-            Some(x) => x,
-            None => {
-                // println!("the AST: ");
-                return Ok(());
+    // Finds the scope that declares the identifier `id` and records where it
+    // lives. Returns that scope, or `None` for a global the file declares later.
+    fn resolve_name(&mut self, id: NodeId) -> Result<Option<&mut Scope>, miette::Report> {
+        let tree = Rc::clone(&self.tree);
+        let name = tree.text(id);
+        let Some(index) = self
+            .vscope
+            .iter()
+            .rposition(|x| x.vlookup.contains_key(name))
+        else {
+            // A function body runs after the whole file has been declared.
+            if !self.functions.is_empty() && self.global_names.contains(name) {
+                self.late_reads.insert(name.to_string());
+                return Ok(None);
             }
+            return self.error("this varible must be declared before use ", Some(id));
         };
-        self.vscope.reverse();
-        println!("WE FIND: {}", output);
-        for i in &self.vscope {
-            if i.vlookup.contains_key(&output) {
-                {
-                    println!("variable with name of: {output}, maps to : {i:?} ");
-                    return Ok(());
-                }
+        if index > 0 {
+            let slot = self.vscope[index].vlookup[name].slot;
+            let depth = (self.vscope.len() - 1 - index) as u32;
+            self.locals.insert(id, Local { depth, slot });
+            self.capture(index, slot);
+        }
+        Ok(Some(&mut self.vscope[index]))
+    }
+    // Records the local in scope `index` as captured by every function around
+    // the reference that it is declared outside of.
+    fn capture(&mut self, index: usize, slot: u32) {
+        for (function, scope) in &self.functions {
+            if *scope <= index {
+                continue;
+            }
+            let local = Local {
+                depth: (scope - 1 - index) as u32,
+                slot,
+            };
+            let captures = self.captures.entry(*function).or_default();
+            if !captures.contains(&local) {
+                captures.push(local);
             }
         }
-        println!("print scope: {:?}", self.vscope);
-        println!("print output: {:?}", output);
-        return self.error("this varible must be declared before use ", Some(x));
+    }
+    pub fn resolve_assignment(
+        &mut self,
+        op: parser::Op,
+        target: NodeId,
+        value: NodeId,
+    ) -> Result<(), miette::Report> {
+        self.resolve_expr(value)?;
+        if self.ext_value(target).is_none() {
+            return self.error("invalid assignment target", Some(target));
+        };
+        // `a += 1` reads `a` before it writes it, like `a++`.
+        if op.is_compound_assignment() {
+            self.resolve_atom(target)?;
+        }
+        let name = self.tree.text(target).to_string();
+        if let Some(scope) = self.resolve_name(target)? {
+            scope.unassigned.remove(&name);
+        }
+        Ok(())
+    }
+    pub fn resolve_atom(&mut self, x: NodeId) -> Result<(), miette::Report> {
+        let tree = Rc::clone(&self.tree);
+        let span = tree.span(x);
+        // A closure in the initializer only reads it once it is called.
+        let innermost = self
+            .vscope
+            .last()
+            .expect("invariant borked: you cant have 0 scopes");
+        if innermost
+            .vlookup
+            .get(tree.text(x))
+            .is_some_and(|x| !x.defined)
+        {
+            return self.error(
+                "a local variable can not be read in its own initializer",
+                Some(x),
+            );
+        }
+        // A function calling itself does not count as a use of it.
+        let enclosing: Vec<NodeId> = self
+            .functions
+            .iter()
+            .filter_map(|(x, _)| match tree[*x] {
+                Tree::Fun { name, .. } => Some(name),
+                _ => None,
+            })
+            .collect();
+        let Some(scope) = self.resolve_name(x)? else {
+            return Ok(());
+        };
+        if let Some(variable) = scope.vlookup.get_mut(tree.text(x))
+            && !enclosing.contains(&variable.decl)
+        {
+            variable.read = true;
+        }
+        if let Some(reads) = scope.unassigned.get_mut(tree.text(x)) {
+            reads.push(span);
+        }
+        Ok(())
     }
 
     pub fn resolve_non_term(
         &mut self,
+        id: NodeId,
         op: &parser::Op,
-        trees: &Vec<Tree>,
+        trees: &[NodeId],
     ) -> Result<(), miette::Report> {
-        if *op == parser::Op::Group {
-            self.vscope.push(Scope::new());
+        match (op, trees) {
+            (parser::Op::Import, _) => self.resolve_import(id),
+            (parser::Op::Break | parser::Op::Continue, _) if self.loop_depth == 0 => {
+                self.error("this can only be used inside a loop", Some(id))
+            }
+            (parser::Op::Group, _) => {
+                self.vscope.push(Scope::new());
+                let output = trees.iter().try_for_each(|x| self.resolve_statement(*x));
+                self.pop_scope();
+                output
+            }
+            // The increment of a lowered `for` comes after the body, see `Op::While`.
+            (parser::Op::While, [cond, body, inc @ ..]) => {
+                self.resolve_expr(*cond)?;
+                self.loop_depth += 1;
+                let output = self.resolve_statement(*body);
+                self.loop_depth -= 1;
+                output?;
+                self.resolve_exprs(inc)
+            }
+            (parser::Op::If, [cond, branches @ ..]) => {
+                self.resolve_expr(*cond)?;
+                branches.iter().try_for_each(|x| self.resolve_statement(*x))
+            }
+            (parser::Op::Return, value) => match self.function_kind {
+                FunctionKind::None => {
+                    self.error("`return` can only be used inside a function", Some(id))
+                }
+                FunctionKind::Initializer if !value.is_empty() => {
+                    self.error("`init` can not return a value", Some(id))
+                }
+                _ => self.resolve_exprs(value),
+            },
+            (parser::Op::Class, [name, superclass @ .., body]) => {
+                self.resolve_class(*name, superclass.first().copied(), *body)
+            }
+            _ => self.resolve_expr_inner(id),
         }
-        for i in trees {
-            self.visit_first(i)?;
+    }
+    // The names an imported file defines become globals, declared by the import.
+    pub fn resolve_import(&mut self, id: NodeId) -> Result<(), miette::Report> {
+        if !self.tree.program.contains(&id) {
+            return self.error("imports must be at the top level of a file", Some(id));
         }
-        if *op == parser::Op::Group {
-            self.vscope.pop();
+        let Some(module) = self.tree.imports.get(&id).map(Rc::clone) else {
+            return self.error("imports can only be loaded when running a file", Some(id));
+        };
+        for name in globals(&module) {
+            self.store_scope(&name, id, Binding::Other)?;
         }
         Ok(())
     }
-    pub fn resolve_fun(
+    // The `fun` declarations directly in the body are its methods, which are
+    // not variables of the body.
+    fn resolve_class(
         &mut self,
-        callee: &Rc<Tree>,
-        arguments: &Vec<Tree>,
+        name: NodeId,
+        superclass: Option<NodeId>,
+        body: NodeId,
     ) -> Result<(), miette::Report> {
-        let out = match &**callee {
-            Tree::Atom(atom) => atom,
-            _ => unreachable!(),
-        };
-        let name = match out {
-            parser::Atom::Ident { range, source } => {
-                let val = &(*source).as_str()[range.start..range.end];
-                String::from(val)
-            }
-            x => {
-                return self.error("only characters are supported as function names: ", Some(x));
+        let ast = Rc::clone(&self.tree);
+        self.store_scope(ast.text(name), name, Binding::Other)?;
+        let mut kind = ClassKind::Class;
+        if let Some(superclass) = superclass {
+            if ast.text(superclass) == ast.text(name) {
+                return self.error("a class can not inherit from itself", Some(superclass));
             }
+            self.resolve_expr(superclass)?;
+            kind = ClassKind::Subclass;
+        }
+        let class_kind = std::mem::replace(&mut self.class_kind, kind);
+        let members = match &ast[body] {
+            Tree::NonTerm(parser::Op::Group, members) => &members[..],
+            _ => std::slice::from_ref(&body),
         };
-        let len = arguments.len();
-        for i in arguments {
-            self.visit_first(i)?;
-        }
-        match self.func_table.get(&name) {
-            Some(x) => {
-                let function = self.fscope.get(*x).unwrap();
-                self.current_id += 1;
-                if len == function.airity {
-                    println!("FUNCTION: {} IS FULLY QUALIFIED", name);
-                    Ok(())
-                } else {
-                    println!("FUNCTION: {} IS NOT FULLY QUALIFIED", name);
-                    panic!("func wrong num args")
-                }
+        self.vscope.push(Scope::new());
+        let output = members.iter().try_for_each(|x| match &ast[*x] {
+            Tree::Fun {
+                name,
+                parameters,
+                body,
+            } => {
+                let kind = match ast.text(*name) {
+                    "init" => FunctionKind::Initializer,
+                    _ => FunctionKind::Method,
+                };
+                self.traced(*x, |resolver, id| {
+                    resolver.resolve_function(id, parameters, *body, kind)
+                })
             }
-            None => {
-                let val = format!("the function must be declared: {}", name);
-                return self.error(&val, None);
+            _ => self.resolve_statement(*x),
+        });
+        self.pop_scope();
+        self.class_kind = class_kind;
+        output
+    }
+    // A call runs the body in one environment with the parameters, so the
+    // statements of a block body share their scope.
+    pub fn resolve_function(
+        &mut self,
+        id: NodeId,
+        parameters: &[NodeId],
+        body: NodeId,
+        kind: FunctionKind,
+    ) -> Result<(), miette::Report> {
+        self.vscope.push(Scope::new());
+        for i in parameters {
+            if let Some(name) = self.ext_value(*i) {
+                self.store_scope(&name, *i, Binding::Parameter)?;
             }
         }
-    }
-    pub fn ext_value(&mut self, input: &parser::Atom) -> Option<String> {
-        let out = match input {
-            parser::Atom::Ident { range, source } => {
-                let val = &(*source).as_str()[range.start..range.end];
-                Some(String::from(val))
+        // An empty entry tells the interpreter the function captures nothing.
+        self.captures.entry(id).or_default();
+        // A loop around the function can not be left from inside its body.
+        let loop_depth = std::mem::take(&mut self.loop_depth);
+        self.functions.push((id, self.vscope.len() - 1));
+        let function_kind = std::mem::replace(&mut self.function_kind, kind);
+        let ast = Rc::clone(&self.tree);
+        let output = match &ast[body] {
+            Tree::NonTerm(parser::Op::Group, trees) => {
+                trees.iter().try_for_each(|x| self.resolve_statement(*x))
             }
-            _ => unreachable!(),
+            _ => self.resolve_statement(body),
         };
-        out
+        self.functions.pop();
+        self.function_kind = function_kind;
+        self.loop_depth = loop_depth;
+        self.pop_scope();
+        output
     }
-    pub fn resolve(&mut self) -> Result<(), miette::Report> {
-        let ast = self.ast.parse_program()?;
-        println!("the AST: {ast:?}");
-        for i in ast {
-            self.visit_first(&i)?;
+    pub fn resolve_fun(
+        &mut self,
+        callee: NodeId,
+        arguments: &[NodeId],
+    ) -> Result<(), miette::Report> {
+        self.resolve_expr(callee)?;
+        self.resolve_exprs(arguments)?;
+        // The arity of a name bound by a `fun` declaration is known ahead, a
+        // value's only at runtime.
+        let Some(name) = self.ext_value(callee) else {
+            return Ok(());
+        };
+        if self.assigned.contains(&name) {
+            return Ok(());
         }
-        Ok(())
-    }
-    fn error(&mut self, message: &str, found: Option<&parser::Atom>) -> Result<(), miette::Report> {
-        let err = match found {
-            Some(x) => {
-                format!("{message}: `{}`", x)
+        let binding = self.vscope.iter().rev().find_map(|x| x.vlookup.get(&name));
+        let Some(variable) = binding.filter(|x| x.kind == Binding::Function) else {
+            return Ok(());
+        };
+        match self.func_table.get(&variable.decl) {
+            Some(x) if self.fscope[*x].airity != arguments.len() => {
+                let msg = format!(
+                    "`{name}` takes {} arguments but is called with {}",
+                    self.fscope[*x].airity,
+                    arguments.len()
+                );
+                self.error(&msg, Some(callee))
             }
-            None => {
-                format!("{message}")
+            _ => Ok(()),
+        }
+    }
+    pub fn ext_value(&mut self, input: NodeId) -> Option<String> {
+        match self.tree[input] {
+            Tree::Atom(parser::Atom::Ident) => Some(String::from(self.tree.text(input))),
+            _ => None,
+        }
+    }
+    /// Parses and resolves a program that imports nothing, files go through [`crate::loader::Loader`].
+    #[allow(dead_code)]
+    pub fn resolve(&mut self) -> Result<(Rc<Ast>, Rc<Resolution>), miette::Report> {
+        let tree = self.ast.parse_program()?;
+        self.resolve_tree(tree)
+    }
+    /// Lowers and resolves an already parsed program, such as one whose imports have been loaded.
+    pub fn resolve_tree(&mut self, tree: Ast) -> Result<(Rc<Ast>, Rc<Resolution>), miette::Report> {
+        self.tree = Rc::new(lower::lower(tree));
+        let mut assignments = Assignments::default();
+        visit::walk_ids(&mut assignments, &self.tree, &self.tree.program);
+        self.assigned = assignments.0;
+        // A global declared again rebinds its name, a function body resolved
+        // before that may run after it.
+        self.global_names.clear();
+        for name in globals(&self.tree) {
+            if !self.global_names.insert(name.clone()) {
+                self.assigned.insert(name);
             }
+        }
+        for i in self.tree.program.clone() {
+            self.resolve_statement(i)?;
+        }
+        let globals = std::mem::take(&mut self.vscope[0].unassigned);
+        self.report_unassigned(globals);
+        // Only the functions of the top level, a global variable may be read by
+        // a file that imports this one.
+        let functions = self.vscope[0]
+            .vlookup
+            .iter()
+            .filter(|(name, x)| x.kind == Binding::Function && !self.late_reads.contains(*name))
+            .map(|(_, x)| *x)
+            .collect();
+        self.report_unused(functions);
+        let resolution = Resolution {
+            locals: std::mem::take(&mut self.locals),
+            captures: std::mem::take(&mut self.captures),
+            imports: std::mem::take(&mut self.imports),
         };
-        return Err(ResolverError {
+        Ok((Rc::clone(&self.tree), Rc::new(resolution)))
+    }
+    fn source_span(&self, id: NodeId) -> SourceSpan {
+        let range = self.tree.span(id);
+        SourceSpan::new(range.start.into(), range.end - range.start)
+    }
+    fn error<T>(&mut self, message: &str, found: Option<NodeId>) -> Result<T, miette::Report> {
+        let (message, range) = match found {
+            Some(x) => (
+                format!("{message}: `{}`", self.tree.sexpr(x)),
+                self.tree.span(x),
+            ),
+            None => (message.to_string(), self.ast.current().range),
+        };
+        Err(ResolverError {
             source: Arc::clone(&self.ast.input),
-            primary_span: parser::token_to_span(&self.ast.current()),
-        })
-        .wrap_err(err);
+            primary_span: SourceSpan::new(range.start.into(), range.end - range.start),
+            message,
+        }
+        .into())
     }
 }
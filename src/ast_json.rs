@@ -0,0 +1,188 @@
+//! Serializes a parsed program as JSON for editors and other external tools.
+//!
+//! The output of `rlox --emit=ast-json FILE` is one object:
+//!
+//! ```json
+//! {"version": 1, "program": [NODE, ..]}
+//! ```
+//!
+//! `version` is bumped whenever a node kind or field is renamed, removed or
+//! changes meaning, adding new kinds or fields does not change it. Every node
+//! is an object with a `kind`, an `id`, a `span` and the fields listed for its
+//! kind:
+//!
+//! | kind                   | fields                                                   |
+//! |------------------------|----------------------------------------------------------|
+//! | `identifier`           | `name`: string                                           |
+//! | `string`               | `value`: string, without the quotes                      |
+//! | `number`               | `value`: number, `null` if it does not fit in an f64     |
+//! | `bool`                 | `value`: bool                                            |
+//! | `nil`                  |                                                          |
+//! | `this`                 |                                                          |
+//! | `super`                |                                                          |
+//! | `error`                | a part of the program that failed to parse               |
+//! | `empty`                | a missing part, such as the condition of `for (;;)`      |
+//! | `operator`             | `op`: string, an operator on its own                     |
+//! | `expression_statement` | `children`: [NODE]                                       |
+//! | `var`                  | `name`: string, `initializer`: NODE or `null`            |
+//! | `call`                 | `callee`: NODE, `arguments`: [NODE]                      |
+//! | `function`             | `name`: NODE, `parameters`: [NODE], `body`: NODE         |
+//! | `lambda`               | `parameters`: [NODE], `body`: NODE                       |
+//! | `operation`            | `op`: string, `children`: [NODE]                         |
+//!
+//! `op` is the operator's spelling in the source (`+`, `==`, `+=`, `and`,
+//! `.`) or, for nodes without a single token, one of `if`, `else`, `for`,
+//! `while`, `print`, `return`, `break`, `continue`, `import`, `class`,
+//! `var`, `group` (blocks and parentheses), `call`, `list` and `index`. `++`
+//! and `--` are the prefix forms, the postfix forms are `post++` and
+//! `post--`. The children of an `operation` are its operands in source
+//! order, an `if` has the condition, the then branch and an optional else
+//! branch, a `for` has the initializer, condition, increment and body, of
+//! which the first three may be `empty` and the initializer may be a `var`, a
+//! `class` has the name, an optional superclass and the body, an `import` has
+//! the path as a `string`. The imported file is not included.
+//!
+//! `id` is a number unique within the program, nodes are numbered in the order
+//! the parser finished them so children come before their parents.
+//!
+//! `span` is `[start, end]`, the byte offsets into the source file the node
+//! was parsed from with `end` exclusive.
+//!
+//! Version 1 had no `id`, and its spans only covered the identifiers and
+//! strings inside a node, `null` when there were none.
+use crate::parser::{Ast, Atom, NodeId, Tree};
+use std::fmt::Write;
+
+pub const VERSION: u32 = 2;
+
+pub fn to_json(ast: &Ast) -> String {
+    let mut output = format!("{{\"version\":{VERSION},\"program\":");
+    list(&mut output, ast, &ast.program);
+    output.push('}');
+    output
+}
+
+fn node(output: &mut String, ast: &Ast, id: NodeId) {
+    let tree = &ast[id];
+    let kind = match tree {
+        Tree::Atom(Atom::Ident) => "identifier",
+        Tree::Atom(Atom::String) => "string",
+        Tree::Atom(Atom::Number(_)) => "number",
+        Tree::Atom(Atom::Bool(_)) => "bool",
+        Tree::Atom(Atom::Nil) => "nil",
+        Tree::Atom(Atom::This) => "this",
+        Tree::Atom(Atom::Super) => "super",
+        Tree::Atom(Atom::Error) => "error",
+        Tree::Nil => "empty",
+        Tree::Op(_) => "operator",
+        Tree::ExprStatment(_) => "expression_statement",
+        Tree::Var(..) => "var",
+        Tree::Call { .. } => "call",
+        Tree::Fun { .. } => "function",
+        Tree::Lambda { .. } => "lambda",
+        Tree::NonTerm(..) => "operation",
+    };
+    let span = ast.span(id);
+    let _ = write!(
+        output,
+        "{{\"kind\":\"{kind}\",\"id\":{},\"span\":[{},{}]",
+        id.index(),
+        span.start,
+        span.end
+    );
+    match tree {
+        Tree::Atom(Atom::Ident) => {
+            output.push_str(",\"name\":");
+            string(output, ast.text(id));
+        }
+        Tree::Atom(Atom::String) => {
+            output.push_str(",\"value\":");
+            string(output, ast.text(id).trim_matches('"'));
+        }
+        Tree::Atom(Atom::Number(x)) if x.is_finite() => {
+            let _ = write!(output, ",\"value\":{x}");
+        }
+        Tree::Atom(Atom::Number(_)) => output.push_str(",\"value\":null"),
+        Tree::Atom(Atom::Bool(x)) => {
+            let _ = write!(output, ",\"value\":{x}");
+        }
+        Tree::Atom(_) | Tree::Nil => {}
+        Tree::Op(op) => {
+            output.push_str(",\"op\":");
+            string(output, op.name());
+        }
+        Tree::ExprStatment(ids) => {
+            output.push_str(",\"children\":");
+            list(output, ast, ids);
+        }
+        Tree::Var(name, val) => {
+            output.push_str(",\"name\":");
+            string(output, ast.text(*name));
+            output.push_str(",\"initializer\":");
+            match val {
+                Some(val) => node(output, ast, *val),
+                None => output.push_str("null"),
+            }
+        }
+        Tree::Call { callee, arguments } => {
+            output.push_str(",\"callee\":");
+            node(output, ast, *callee);
+            output.push_str(",\"arguments\":");
+            list(output, ast, arguments);
+        }
+        Tree::Fun {
+            name,
+            parameters,
+            body,
+        } => {
+            output.push_str(",\"name\":");
+            node(output, ast, *name);
+            output.push_str(",\"parameters\":");
+            list(output, ast, parameters);
+            output.push_str(",\"body\":");
+            node(output, ast, *body);
+        }
+        Tree::Lambda { parameters, body } => {
+            output.push_str(",\"parameters\":");
+            list(output, ast, parameters);
+            output.push_str(",\"body\":");
+            node(output, ast, *body);
+        }
+        Tree::NonTerm(op, ids) => {
+            output.push_str(",\"op\":");
+            string(output, op.name());
+            output.push_str(",\"children\":");
+            list(output, ast, ids);
+        }
+    }
+    output.push('}');
+}
+
+fn list(output: &mut String, ast: &Ast, ids: &[NodeId]) {
+    output.push('[');
+    for (i, id) in ids.iter().enumerate() {
+        if i > 0 {
+            output.push(',');
+        }
+        node(output, ast, *id);
+    }
+    output.push(']');
+}
+
+fn string(output: &mut String, text: &str) {
+    output.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => output.push_str("\\\""),
+            '\\' => output.push_str("\\\\"),
+            '\n' => output.push_str("\\n"),
+            '\r' => output.push_str("\\r"),
+            '\t' => output.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(output, "\\u{:04x}", c as u32);
+            }
+            c => output.push(c),
+        }
+    }
+    output.push('"');
+}
@@ -0,0 +1,246 @@
+use crate::parser;
+use crate::scanner::{Token, TokenType};
+use crate::source::Source;
+
+const INDENT: &str = "    ";
+
+// What has to be written between the last token and the next one.
+#[derive(PartialEq, Clone, Copy)]
+enum Pending {
+    Nothing,
+    Space,
+    Newline,
+}
+
+/// Re-emits a program with canonical indentation, brace placement and spacing.
+///
+/// The file is parsed first so only valid programs get formatted, then the
+/// output is rebuilt from the scanner's tokens, with `//` comments and single
+/// blank lines recovered from the source between token ranges. Formatting
+/// already formatted code gives back the same text.
+pub fn format(source: impl Into<Source>) -> Result<String, miette::Report> {
+    let mut parser = parser::Parser::new(source)?;
+    parser.parse_program()?;
+    let source = parser.input.text.as_str();
+    let tokens = parser.stream;
+    let mut fmt = Formatter {
+        output: String::new(),
+        indent: 0,
+        parens: 0,
+        pending: Pending::Nothing,
+    };
+    let mut prev: Option<&Token> = None;
+    let mut last_end = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.kind == TokenType::Eof {
+            continue;
+        }
+        fmt.gap(&source[last_end..token.range.start], prev, token);
+        fmt.token(&source[token.range.clone()], prev, token, tokens.get(i + 1));
+        prev = Some(token);
+        last_end = token.range.end;
+    }
+    fmt.comments(&source[last_end..]);
+    let mut output = fmt.output.trim_end().to_string();
+    if !output.is_empty() {
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+struct Formatter {
+    output: String,
+    indent: usize,
+    //Open ( and [, a ; inside them (a for clause) does not end the line
+    parens: usize,
+    pending: Pending,
+}
+
+impl Formatter {
+    // Writes the comments found in the whitespace before `next`.
+    fn gap(&mut self, gap: &str, prev: Option<&Token>, next: &Token) {
+        let newlines = self.comments(gap);
+        if self.pending == Pending::Newline {
+            let after_open = prev.is_some_and(|x| x.kind == TokenType::LeftBrace);
+            let blank = newlines > 1 && !after_open && next.kind != TokenType::RightBrace;
+            self.start_line(blank);
+        }
+    }
+
+    // Writes each comment either after the code on its line or on a line of its own,
+    // returns how many line breaks follow the last one.
+    fn comments(&mut self, gap: &str) -> usize {
+        let mut newlines = 0;
+        for part in split_gap(gap) {
+            match part {
+                Gap::Newlines(n) => newlines += n,
+                Gap::Comment(text) => {
+                    if newlines == 0 && !self.output.is_empty() {
+                        self.output.push_str("  ");
+                    } else {
+                        self.start_line(newlines > 1);
+                        self.output.push_str(&INDENT.repeat(self.indent));
+                    }
+                    self.output.push_str(text);
+                    self.pending = Pending::Newline;
+                    newlines = 0;
+                }
+            }
+        }
+        newlines
+    }
+
+    fn token(&mut self, text: &str, prev: Option<&Token>, token: &Token, next: Option<&Token>) {
+        match token.kind {
+            TokenType::RightBrace => {
+                self.indent = self.indent.saturating_sub(1);
+                if prev.is_none_or(|x| x.kind != TokenType::LeftBrace) {
+                    self.start_line(false);
+                }
+            }
+            TokenType::RightParen | TokenType::RightHardBrace => {
+                self.parens = self.parens.saturating_sub(1);
+            }
+            _ => {}
+        }
+        if self.pending == Pending::Newline {
+            self.start_line(false);
+        }
+        if self.output.ends_with('\n') || self.output.is_empty() {
+            self.output.push_str(&INDENT.repeat(self.indent));
+        } else if self.pending == Pending::Space && space_between(prev, token) {
+            self.output.push(' ');
+        }
+        self.output.push_str(text);
+        let next = next.map_or(TokenType::Eof, |x| x.kind);
+        self.pending = match token.kind {
+            TokenType::LeftBrace => {
+                self.indent += 1;
+                if next == TokenType::RightBrace {
+                    Pending::Nothing
+                } else {
+                    Pending::Newline
+                }
+            }
+            TokenType::LeftParen | TokenType::LeftHardBrace => {
+                self.parens += 1;
+                Pending::Nothing
+            }
+            TokenType::Semicolon if self.parens == 0 => Pending::Newline,
+            TokenType::Bang => Pending::Nothing,
+            TokenType::Minus | TokenType::PlusPlus | TokenType::MinusMinus
+                if !prev.is_some_and(|x| ends_operand(x.kind)) && !merges(token.kind, next) =>
+            {
+                Pending::Nothing
+            }
+            TokenType::RightBrace => match next {
+                TokenType::Else
+                | TokenType::Semicolon
+                | TokenType::Comma
+                | TokenType::RightParen
+                | TokenType::RightHardBrace
+                | TokenType::LeftParen
+                | TokenType::Dot => Pending::Space,
+                _ => Pending::Newline,
+            },
+            _ => Pending::Space,
+        };
+    }
+
+    // Ends the current line (and optionally leaves one blank line) unless already at a line start.
+    fn start_line(&mut self, blank: bool) {
+        if !self.output.is_empty() && !self.output.ends_with('\n') {
+            self.output.push('\n');
+        }
+        if blank && !self.output.is_empty() && !self.output.ends_with("\n\n") {
+            self.output.push('\n');
+        }
+        self.pending = Pending::Nothing;
+    }
+}
+
+enum Gap<'a> {
+    Newlines(usize),
+    Comment(&'a str),
+}
+
+// Splits the source between two tokens into line breaks and `//` comments.
+fn split_gap(mut gap: &str) -> Vec<Gap<'_>> {
+    let mut output = Vec::new();
+    loop {
+        let rest = gap.trim_start_matches(|c: char| c.is_whitespace() || c == '\0');
+        let newlines = gap[..gap.len() - rest.len()].matches('\n').count();
+        if newlines > 0 {
+            output.push(Gap::Newlines(newlines));
+        }
+        if !rest.starts_with("//") {
+            break;
+        }
+        let end = rest.find('\n').unwrap_or(rest.len());
+        output.push(Gap::Comment(rest[..end].trim_end()));
+        gap = &rest[end..];
+    }
+    output
+}
+
+// Whether two tokens written without a space would scan as another token, `- -a` is not
+// `--a`.
+fn merges(prev: TokenType, next: TokenType) -> bool {
+    matches!(
+        (prev, next),
+        (
+            TokenType::Minus | TokenType::MinusMinus,
+            TokenType::Minus | TokenType::MinusMinus
+        ) | (
+            TokenType::Plus | TokenType::PlusPlus,
+            TokenType::Plus | TokenType::PlusPlus
+        )
+    )
+}
+
+// Whether a token ends an operand, so a following `-` is binary, `(`/`[` is a call or index
+// and `++`/`--` is postfix.
+fn ends_operand(kind: TokenType) -> bool {
+    matches!(
+        kind,
+        TokenType::Identifier
+            | TokenType::Number(_)
+            | TokenType::String
+            | TokenType::True
+            | TokenType::False
+            | TokenType::Nil
+            | TokenType::This
+            | TokenType::Super
+            | TokenType::RightParen
+            | TokenType::RightHardBrace
+            | TokenType::PlusPlus
+            | TokenType::MinusMinus
+    )
+}
+
+fn space_between(prev: Option<&Token>, next: &Token) -> bool {
+    let Some(prev) = prev else {
+        return false;
+    };
+    match (prev.kind, next.kind) {
+        (x, y) if merges(x, y) => true,
+        (TokenType::LeftParen | TokenType::LeftHardBrace | TokenType::Dot, _) => false,
+        (
+            _,
+            TokenType::RightParen
+            | TokenType::RightHardBrace
+            | TokenType::Semicolon
+            | TokenType::Comma
+            | TokenType::Dot,
+        ) => false,
+        (TokenType::RightBrace, TokenType::LeftParen) => false,
+        (
+            x,
+            TokenType::LeftParen
+            | TokenType::LeftHardBrace
+            | TokenType::PlusPlus
+            | TokenType::MinusMinus,
+        ) if ends_operand(x) => false,
+        _ => true,
+    }
+}
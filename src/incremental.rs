@@ -0,0 +1,313 @@
+//! Reparsing after an edit, for editors that parse the file on every keystroke.
+use crate::parser::{Ast, NodeId, Parser, Tree};
+use crate::scanner::{self, Scanner, Token, TokenType};
+use crate::source::Source;
+use miette::{Diagnostic, SourceSpan};
+use std::{collections::VecDeque, ops::Range, rc::Rc, sync::Arc};
+
+/// Replaces the bytes `range` of a text with `text`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+#[derive(Debug, Diagnostic)]
+#[diagnostic(help("an edit replaces whole characters of the text it is applied to"))]
+pub struct EditError {
+    #[source_code]
+    source: Arc<Source>,
+    #[label("edited here")]
+    primary_span: SourceSpan,
+    range: Range<usize>,
+}
+
+impl std::error::Error for EditError {}
+impl std::fmt::Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the edit of bytes {}..{} is not within the text or splits a character",
+            self.range.start, self.range.end
+        )
+    }
+}
+
+/// A parse that keeps what [`Document::edit`] needs to redo only the part of
+/// the program an edit touched.
+///
+/// Every top level statement keeps its own tree and the tokens it was parsed
+/// from, both relative to where the statement starts, so an edit before it
+/// only moves that start. The statements before an edit are shared with the
+/// old document and scanning resumes after them, until a token past the edit
+/// starts where an old one did. The text ahead of both is the same, so from
+/// there on the old tokens are reused, and once the parser reaches the start of
+/// an old statement, so are that statement and all that follow it. An edit
+/// that spans the whole program, or after which the tokens never line up, ends
+/// up parsing everything, the same as [`Document::parse`].
+///
+/// An edit copies the text once and touches every statement to move it, the
+/// tokens and trees of the statements it did not change are shared.
+#[derive(Debug, Clone)]
+pub struct Document {
+    source: Arc<Source>,
+    statements: Vec<Statement>,
+}
+
+/// A top level statement of a [`Document`].
+#[derive(Debug, Clone)]
+pub struct Statement {
+    /// Where the statement starts in the text of the document.
+    pub start: usize,
+    /// A program of just this statement, empty for an expression without a
+    /// semicolon, which the parser leaves out. Its source is the statement's
+    /// own text, so the spans are relative to `start`.
+    pub ast: Rc<Ast>,
+    //The tokens it was parsed from, relative to `start`
+    tokens: Rc<[Token]>,
+}
+
+impl Statement {
+    // Where its first token ends in the text of the document.
+    fn first_end(&self) -> usize {
+        self.start + self.tokens.first().map_or(0, |x| x.range.end)
+    }
+}
+
+impl Document {
+    pub fn parse(source: impl Into<Source>) -> Result<Document, miette::Report> {
+        let source = source.into();
+        // Nothing of an empty document can be reused, so inserting all of the
+        // text parses all of it.
+        let empty = Document {
+            source: Arc::new(Source::new(source.name, "")),
+            statements: Vec::new(),
+        };
+        let edit = Edit {
+            range: 0..0,
+            text: source.text,
+        };
+        Ok(empty.edit(&edit)?.0)
+    }
+
+    pub fn source(&self) -> &Arc<Source> {
+        &self.source
+    }
+
+    /// The top level statements, in source order.
+    pub fn statements(&self) -> impl Iterator<Item = &Statement> {
+        self.statements.iter().filter(|x| !x.ast.program.is_empty())
+    }
+
+    /// The whole program as one tree, as [`Parser::parse_program`] gives it.
+    /// This copies every statement, unlike an edit.
+    pub fn ast(&self) -> Ast {
+        let mut ast = Ast::new(Arc::clone(&self.source));
+        for x in self.statements() {
+            let id = copy(&x.ast, x.ast.program[0], &mut ast, x.start as isize);
+            ast.program.push(id);
+        }
+        ast
+    }
+
+    /// Applies `edit` to the text and parses the result. Returns the new document
+    /// and the spans of the top level statements that were parsed again, every
+    /// other statement is shared with this one. On a syntax error, or an edit
+    /// outside of the text, this document stays the last good parse.
+    pub fn edit(&self, edit: &Edit) -> Result<(Document, Vec<Range<usize>>), miette::Report> {
+        let old = &self.source.text;
+        let range = edit.range.clone();
+        if range.start > range.end
+            || !old.is_char_boundary(range.start)
+            || !old.is_char_boundary(range.end)
+        {
+            return Err(self.bad_edit(range));
+        }
+        let mut text = old.clone();
+        text.replace_range(range.clone(), &edit.text);
+        let source = Arc::new(Source::new(self.source.name.clone(), text));
+        let shift = edit.text.len() as isize - range.len() as isize;
+        let moved = |x: usize| (x as isize + shift) as usize;
+
+        // The parser peeks at the token after a statement to see where it
+        // ends, and the scanner may look two characters past a token, `1.` is
+        // only a number if a digit follows, so those have to be before the edit too.
+        let kept = self.statements.get(1..).map_or(0, |rest| {
+            rest.partition_point(|x| x.first_end() + 2 <= range.start)
+        });
+        let restart = match kept {
+            0 => 0,
+            _ => self.statements[kept].start,
+        };
+        let inserted_end = range.start + edit.text.len();
+        //The first old token that lines up again, by statement and index in it
+        let mut rejoined = None;
+        let mut tokens = Vec::new();
+        for token in Scanner::starting_at(&source, restart) {
+            let token = token?;
+            if token.range.start >= inserted_end {
+                let start = token.range.start - inserted_end + range.end;
+                if let Some(at) = self.find_token(start) {
+                    rejoined = Some(at);
+                    break;
+                }
+            }
+            tokens.push(token);
+        }
+
+        let mut parser = Parser::with_tokens(Arc::clone(&source), tokens);
+        let mut feed = Feed {
+            next: rejoined,
+            boundaries: VecDeque::new(),
+            added: 0,
+            eof: scanner::eof(&source.text),
+        };
+        // The old tokens are handed over a statement at a time as the parser
+        // needs them, the end of input behind them is only real after the last.
+        feed.extend(self, &mut parser.stream, 1, shift);
+        let mut statements = self.statements[..kept].to_vec();
+        let mut changed = Vec::new();
+        loop {
+            while feed.boundaries.front().is_some_and(|x| x.0 < parser.pos) {
+                feed.boundaries.pop_front();
+            }
+            // From the start of an old statement on, the tokens are the same
+            // and so is the rest of the parse.
+            if let Some(&(_, i)) = feed.boundaries.front().filter(|x| x.0 == parser.pos) {
+                statements.extend(self.statements[i..].iter().map(|x| Statement {
+                    start: moved(x.start),
+                    ..x.clone()
+                }));
+                break;
+            }
+            if feed.next.is_none() && parser.peek().kind == TokenType::Eof {
+                break;
+            }
+            let start = parser.pos;
+            let output = parser.parse_statment();
+            // Cut short by the end of the tokens added so far, not of the text.
+            if feed.next.is_some() && (output.is_err() || parser.pos + 1 >= parser.stream.len()) {
+                parser.pos = start;
+                // Twice as many each time, so a long statement is not parsed over and over.
+                let count = feed.added.max(1);
+                feed.extend(self, &mut parser.stream, count, shift);
+                continue;
+            }
+            let id = output?;
+            let first = parser.stream[start].range.start;
+            let end = parser.stream[parser.pos - 1].range.end;
+            let text = Source::new(source.name.clone(), &source.text[first..end]);
+            let mut ast = Ast::new(Arc::new(text));
+            if !matches!(parser.ast[id], Tree::Atom(_)) {
+                changed.push(parser.ast.span(id));
+                let id = copy(&parser.ast, id, &mut ast, -(first as isize));
+                ast.program.push(id);
+            }
+            let tokens = parser.stream[start..parser.pos].iter().map(|x| Token {
+                kind: x.kind,
+                range: x.range.start - first..x.range.end - first,
+            });
+            statements.push(Statement {
+                start: first,
+                ast: Rc::new(ast),
+                tokens: tokens.collect(),
+            });
+        }
+        let document = Document { source, statements };
+        Ok((document, changed))
+    }
+
+    // The statement and index in it of the old token that starts at byte `start`.
+    fn find_token(&self, start: usize) -> Option<(usize, usize)> {
+        let i = self.statements.partition_point(|x| x.start <= start);
+        let statement = &self.statements[i.checked_sub(1)?];
+        let relative = start - statement.start;
+        let j = statement
+            .tokens
+            .binary_search_by_key(&relative, |x| x.range.start)
+            .ok()?;
+        Some((i - 1, j))
+    }
+
+    fn bad_edit(&self, range: Range<usize>) -> miette::Report {
+        let len = self.source.text.len();
+        let start = range.start.min(len);
+        miette::Report::new(EditError {
+            source: Arc::clone(&self.source),
+            primary_span: SourceSpan::new(start.into(), range.end.clamp(start, len) - start),
+            range,
+        })
+    }
+}
+
+// The old tokens after the point they line up again, handed to the parser as
+// it needs them.
+struct Feed {
+    //The statement and index in it of the next old token to add
+    next: Option<(usize, usize)>,
+    //Where in the parser's tokens each old statement added so far starts
+    boundaries: VecDeque<(usize, usize)>,
+    //How many old statements were added so far
+    added: usize,
+    eof: Token,
+}
+
+impl Feed {
+    // Adds the tokens of up to `count` more old statements to `stream`, moved
+    // by `shift` bytes, before the end of input.
+    fn extend(&mut self, document: &Document, stream: &mut Vec<Token>, count: usize, shift: isize) {
+        if stream.last().is_some_and(|x| x.kind == TokenType::Eof) {
+            stream.pop();
+        }
+        for _ in 0..count {
+            let Some((i, j)) = self.next else {
+                break;
+            };
+            if j == 0 {
+                self.boundaries.push_back((stream.len(), i));
+            }
+            let statement = &document.statements[i];
+            let start = statement.start as isize + shift;
+            stream.extend(statement.tokens[j..].iter().map(|x| Token {
+                kind: x.kind,
+                range: (x.range.start as isize + start) as usize
+                    ..(x.range.end as isize + start) as usize,
+            }));
+            self.next = (i + 1 < document.statements.len()).then_some((i + 1, 0));
+            self.added += 1;
+        }
+        stream.push(self.eof.clone());
+    }
+}
+
+// Copies the subtree at `id` into `to`, children first like the parser adds
+// them, moving every span by `shift` bytes.
+fn copy(from: &Ast, id: NodeId, to: &mut Ast, shift: isize) -> NodeId {
+    let mut map = |x: &NodeId| copy(from, *x, to, shift);
+    let tree = match &from[id] {
+        Tree::ExprStatment(ids) => Tree::ExprStatment(ids.iter().map(&mut map).collect()),
+        Tree::Var(name, value) => Tree::Var(map(name), value.as_ref().map(&mut map)),
+        Tree::Call { callee, arguments } => Tree::Call {
+            callee: map(callee),
+            arguments: arguments.iter().map(&mut map).collect(),
+        },
+        Tree::NonTerm(op, ids) => Tree::NonTerm(*op, ids.iter().map(&mut map).collect()),
+        Tree::Fun {
+            name,
+            parameters,
+            body,
+        } => Tree::Fun {
+            name: map(name),
+            parameters: parameters.iter().map(&mut map).collect(),
+            body: map(body),
+        },
+        Tree::Lambda { parameters, body } => Tree::Lambda {
+            parameters: parameters.iter().map(&mut map).collect(),
+            body: map(body),
+        },
+        leaf @ (Tree::Nil | Tree::Atom(_) | Tree::Op(_)) => leaf.clone(),
+    };
+    let span = from.span(id);
+    let moved = |x: usize| (x as isize + shift) as usize;
+    to.push(tree, moved(span.start)..moved(span.end))
+}
@@ -0,0 +1,739 @@
+use crate::analysis::{Local, Resolution};
+use crate::parser::{Ast, Atom, NodeId, Op, Tree};
+use crate::source::Source;
+use miette::{Context, Diagnostic, Result, SourceSpan};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{Stdout, Write},
+    rc::Rc,
+    sync::Arc,
+};
+
+/// How many calls may be running at once before the program is stopped with
+/// a stack overflow, instead of overflowing the interpreter's own stack.
+pub const MAX_CALL_DEPTH: usize = 256;
+
+#[derive(Debug, Diagnostic)]
+#[diagnostic(help("this is a runtime error"))]
+pub struct RuntimeError {
+    #[source_code]
+    source: Arc<Source>,
+    #[label("main issue")]
+    primary_span: SourceSpan,
+}
+
+impl std::error::Error for RuntimeError {}
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "error while running the program")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(Rc<str>),
+    Function(Rc<Function>),
+    List(List),
+}
+
+pub type List = Rc<RefCell<Vec<Value>>>;
+
+#[derive(Debug)]
+pub struct Function {
+    pub name: Option<String>,
+    //Identifier nodes of `ast`
+    pub parameters: Vec<NodeId>,
+    //The program the body was parsed in.
+    pub ast: Rc<Ast>,
+    //What the resolver learned about `ast`
+    pub resolution: Rc<Resolution>,
+    pub body: NodeId,
+    //The environment the function was created in, shared so later assignments are seen.
+    pub closure: Rc<RefCell<Environment>>,
+}
+
+#[derive(Debug, Default)]
+pub struct Environment {
+    //Variables by name, the globals and those of a program that was not resolved
+    pub values: HashMap<String, Value>,
+    //Locals by the slot the resolver gave them
+    pub slots: Vec<Value>,
+    pub enclosing: Option<Rc<RefCell<Environment>>>,
+}
+
+/// How a statement finished, `Return` unwinds up to the enclosing call and
+/// `Break`/`Continue` up to the enclosing loop.
+pub enum Flow {
+    Normal,
+    Return(Value),
+    Break,
+    Continue,
+}
+
+/// Runs a resolved program, `print` statements write to `out` (stdout unless
+/// an embedder hands in its own writer through [`Interpreter::with_output`]).
+pub struct Interpreter<W: Write = Stdout> {
+    //The innermost scope, the globals once the program has finished running.
+    pub env: Rc<RefCell<Environment>>,
+    //The outermost scope
+    pub globals: Rc<RefCell<Environment>>,
+    //The program being run, switched while a function from another one runs.
+    pub ast: Rc<Ast>,
+    //What the resolver learned about `ast`, switched along with it
+    pub resolution: Rc<Resolution>,
+    //Files that an import has run already, each one only runs once
+    pub imported: Vec<Rc<Ast>>,
+    //How many calls are running
+    pub depth: usize,
+    pub out: W,
+}
+
+impl Environment {
+    pub fn new(enclosing: Option<Rc<RefCell<Environment>>>) -> Self {
+        Self {
+            values: HashMap::new(),
+            slots: Vec::new(),
+            enclosing,
+        }
+    }
+    pub fn define(&mut self, name: &str, value: Value) {
+        self.values.insert(String::from(name), value);
+    }
+    pub fn get(&self, name: &str) -> Option<Value> {
+        match self.values.get(name) {
+            Some(x) => Some(x.clone()),
+            None => self.enclosing.as_ref()?.borrow().get(name),
+        }
+    }
+    pub fn assign(&mut self, name: &str, value: Value) -> bool {
+        if let Some(slot) = self.values.get_mut(name) {
+            *slot = value;
+            return true;
+        }
+        match &self.enclosing {
+            Some(x) => x.borrow_mut().assign(name, value),
+            None => false,
+        }
+    }
+    // A declaration that was skipped, such as one in an `if` that did not
+    // run, leaves its slot nil.
+    pub fn define_at(&mut self, slot: u32, value: Value) {
+        let slot = slot as usize;
+        if self.slots.len() <= slot {
+            self.slots.resize(slot + 1, Value::Nil);
+        }
+        self.slots[slot] = value;
+    }
+    pub fn get_at(&self, local: Local) -> Option<Value> {
+        match local.depth {
+            0 => self.slots.get(local.slot as usize).cloned(),
+            depth => {
+                let outer = Local {
+                    depth: depth - 1,
+                    ..local
+                };
+                self.enclosing.as_ref()?.borrow().get_at(outer)
+            }
+        }
+    }
+    pub fn assign_at(&mut self, local: Local, value: Value) -> bool {
+        match local.depth {
+            0 => match self.slots.get_mut(local.slot as usize) {
+                Some(slot) => {
+                    *slot = value;
+                    true
+                }
+                None => false,
+            },
+            depth => {
+                let outer = Local {
+                    depth: depth - 1,
+                    ..local
+                };
+                match &self.enclosing {
+                    Some(x) => x.borrow_mut().assign_at(outer, value),
+                    None => false,
+                }
+            }
+        }
+    }
+}
+
+impl Value {
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Function(_) => "function",
+            Value::List(_) => "list",
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(x) => write!(f, "{x}"),
+            Value::Number(x) => write!(f, "{x}"),
+            Value::String(x) => write!(f, "{x}"),
+            Value::Function(x) => match &x.name {
+                Some(name) => write!(f, "<fn {name}>"),
+                None => write!(f, "<fn>"),
+            },
+            Value::List(x) => {
+                write!(f, "[")?;
+                for (i, item) in x.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+impl Interpreter<Stdout> {
+    pub fn new() -> Self {
+        Self::with_output(std::io::stdout())
+    }
+}
+
+impl Default for Interpreter<Stdout> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> Interpreter<W> {
+    pub fn with_output(out: W) -> Self {
+        let globals = Rc::new(RefCell::new(Environment::new(None)));
+        Self {
+            env: Rc::clone(&globals),
+            globals,
+            ast: Rc::new(Ast::new(Arc::new(Source::default()))),
+            resolution: Rc::default(),
+            imported: Vec::new(),
+            depth: 0,
+            out,
+        }
+    }
+
+    /// Runs the top level statements of `ast` in the global scope, with the
+    /// locals `resolution` found in it.
+    pub fn interpret(
+        &mut self,
+        ast: &Rc<Ast>,
+        resolution: &Rc<Resolution>,
+    ) -> Result<(), miette::Report> {
+        self.ast = Rc::clone(ast);
+        self.resolution = Rc::clone(resolution);
+        for i in ast.program.iter() {
+            self.execute(*i)?;
+        }
+        Ok(())
+    }
+
+    pub fn execute(&mut self, stmt: NodeId) -> Result<Flow, miette::Report> {
+        let ast = Rc::clone(&self.ast);
+        match &ast[stmt] {
+            Tree::Var(name, val) => {
+                let value = match val {
+                    Some(x) => self.evaluate(*x)?,
+                    None => Value::Nil,
+                };
+                self.define(&self.env, *name, value);
+            }
+            Tree::Fun {
+                name,
+                parameters,
+                body,
+            } => {
+                let function = self.make_function(stmt, Some(ast.text(*name)), parameters, *body);
+                self.define(&self.env, *name, function);
+            }
+            Tree::ExprStatment(ids) => {
+                for i in ids {
+                    self.evaluate(*i)?;
+                }
+            }
+            Tree::NonTerm(Op::Group, ids) => {
+                let env = Environment::new(Some(Rc::clone(&self.env)));
+                return self.execute_block(ids, Rc::new(RefCell::new(env)));
+            }
+            Tree::NonTerm(Op::If, ids) => {
+                if self.evaluate(ids[0])?.is_truthy() {
+                    return self.execute(ids[1]);
+                } else if let Some(other) = ids.get(2) {
+                    return self.execute(*other);
+                }
+            }
+            // The third child is the increment of a lowered `for`, see `Op::While`.
+            Tree::NonTerm(Op::While, ids) => {
+                while self.evaluate(ids[0])?.is_truthy() {
+                    match self.execute(ids[1])? {
+                        Flow::Return(x) => return Ok(Flow::Return(x)),
+                        Flow::Break => break,
+                        // `continue` still runs the increment.
+                        Flow::Normal | Flow::Continue => {}
+                    }
+                    if let Some(inc) = ids.get(2) {
+                        self.evaluate(*inc)?;
+                    }
+                }
+            }
+            Tree::NonTerm(Op::Print, ids) => {
+                let value = self.evaluate(ids[0])?;
+                if writeln!(self.out, "{value}").is_err() {
+                    return self.error("could not write the output of print", stmt);
+                }
+            }
+            Tree::NonTerm(Op::Return, ids) => {
+                let value = match ids.first() {
+                    Some(x) => self.evaluate(*x)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value));
+            }
+            Tree::NonTerm(Op::Import, _) => {
+                let Some(module) = ast.imports.get(&stmt) else {
+                    return self.error("this import was not loaded", stmt);
+                };
+                if !self.imported.iter().any(|x| Rc::ptr_eq(x, module)) {
+                    self.imported.push(Rc::clone(module));
+                    let resolution = self.resolution.imports.get(&stmt).cloned();
+                    let previous = std::mem::replace(&mut self.ast, Rc::clone(module));
+                    let resolved =
+                        std::mem::replace(&mut self.resolution, resolution.unwrap_or_default());
+                    let output = module
+                        .program
+                        .iter()
+                        .try_for_each(|x| self.execute(*x).map(|_| ()));
+                    self.ast = previous;
+                    self.resolution = resolved;
+                    output?;
+                }
+            }
+            Tree::NonTerm(Op::Break, _) => return Ok(Flow::Break),
+            Tree::NonTerm(Op::Continue, _) => return Ok(Flow::Continue),
+            Tree::NonTerm(Op::Class, _) => {
+                return self.error("classes are not supported at runtime yet", stmt);
+            }
+            _ => {
+                self.evaluate(stmt)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    pub fn execute_block(
+        &mut self,
+        ids: &[NodeId],
+        env: Rc<RefCell<Environment>>,
+    ) -> Result<Flow, miette::Report> {
+        let previous = std::mem::replace(&mut self.env, env);
+        let mut output = Ok(Flow::Normal);
+        for i in ids {
+            output = self.execute(*i);
+            if !matches!(output, Ok(Flow::Normal)) {
+                break;
+            }
+        }
+        self.env = previous;
+        output
+    }
+
+    pub fn evaluate(&mut self, expr: NodeId) -> Result<Value, miette::Report> {
+        let ast = Rc::clone(&self.ast);
+        match &ast[expr] {
+            Tree::Atom(atom) => self.evaluate_atom(atom, expr),
+            Tree::Lambda { parameters, body } => {
+                Ok(self.make_function(expr, None, parameters, *body))
+            }
+            Tree::Call { callee, arguments } => {
+                let function = self.evaluate(*callee)?;
+                let mut values = Vec::with_capacity(arguments.len());
+                for i in arguments {
+                    values.push(self.evaluate(*i)?);
+                }
+                self.call(function, values, expr)
+            }
+            Tree::NonTerm(op, ids) => self.evaluate_non_term(op, ids, expr),
+            Tree::ExprStatment(ids) => {
+                let mut output = Value::Nil;
+                for i in ids {
+                    output = self.evaluate(*i)?;
+                }
+                Ok(output)
+            }
+            _ => self.error("this can not be used as a value", expr),
+        }
+    }
+
+    fn evaluate_atom(&mut self, atom: &Atom, expr: NodeId) -> Result<Value, miette::Report> {
+        match atom {
+            Atom::Number(x) => Ok(Value::Number(*x)),
+            Atom::Bool(x) => Ok(Value::Bool(*x)),
+            Atom::Nil => Ok(Value::Nil),
+            Atom::String => {
+                // The token range includes the surrounding quotes.
+                let text = self.ast.text(expr);
+                Ok(Value::String(Rc::from(&text[1..text.len() - 1])))
+            }
+            Atom::Ident => match self.lookup(expr) {
+                Some(x) => Ok(x),
+                None => {
+                    let msg = format!("undefined variable `{}`", self.ast.text(expr));
+                    self.error(&msg, expr)
+                }
+            },
+            _ => self.error("this can not be used as a value", expr),
+        }
+    }
+
+    fn evaluate_non_term(
+        &mut self,
+        op: &Op,
+        ids: &[NodeId],
+        expr: NodeId,
+    ) -> Result<Value, miette::Report> {
+        let ast = Rc::clone(&self.ast);
+        match (op, ids) {
+            (Op::Group, [x]) => self.evaluate(*x),
+            (Op::Minus, [x]) => match self.evaluate(*x)? {
+                Value::Number(x) => Ok(Value::Number(-x)),
+                other => self.type_error(op, &[other], expr),
+            },
+            (Op::Bang, [x]) => Ok(Value::Bool(!self.evaluate(*x)?.is_truthy())),
+            (Op::List, items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for i in items {
+                    values.push(self.evaluate(*i)?);
+                }
+                Ok(Value::List(Rc::new(RefCell::new(values))))
+            }
+            (Op::Index, [list, index]) => {
+                let (list, index) = self.evaluate_index(*list, *index, expr)?;
+                Ok(list.borrow()[index].clone())
+            }
+            (Op::Equal, [target, val]) => match &ast[*target] {
+                Tree::NonTerm(Op::Index, place) => {
+                    let (list, index) = self.evaluate_index(place[0], place[1], expr)?;
+                    let value = self.evaluate(*val)?;
+                    list.borrow_mut()[index] = value.clone();
+                    Ok(value)
+                }
+                Tree::Atom(Atom::Ident) => {
+                    let value = self.evaluate(*val)?;
+                    if !self.assign(*target, value.clone()) {
+                        let msg = format!("undefined variable `{}`", ast.text(*target));
+                        return self.error(&msg, *target);
+                    }
+                    Ok(value)
+                }
+                _ => {
+                    self.evaluate(*val)?;
+                    self.error("invalid assignment target", *target)
+                }
+            },
+            (op, [target, val]) if op.is_compound_assignment() => {
+                let op = match op {
+                    Op::PlusEqual => Op::Plus,
+                    Op::MinusEqual => Op::Minus,
+                    Op::StarEqual => Op::Star,
+                    Op::SlashEqual => Op::Slash,
+                    _ => Op::Percent,
+                };
+                let (_, new) = self.update(*target, expr, |this, old| {
+                    let rhs = this.evaluate(*val)?;
+                    this.binary(&op, old, rhs, expr)
+                })?;
+                Ok(new)
+            }
+            (Op::PlusPlus | Op::MinusMinus | Op::PostPlusPlus | Op::PostMinusMinus, [target]) => {
+                let step = match op {
+                    Op::PlusPlus | Op::PostPlusPlus => 1.0,
+                    _ => -1.0,
+                };
+                let (old, new) = self.update(*target, expr, |this, old| match old {
+                    Value::Number(x) => Ok(Value::Number(x + step)),
+                    other => this.type_error(op, &[other], expr),
+                })?;
+                match op {
+                    Op::PlusPlus | Op::MinusMinus => Ok(new),
+                    _ => Ok(old),
+                }
+            }
+            (Op::And, [lhs, rhs]) => {
+                let lhs = self.evaluate(*lhs)?;
+                if !lhs.is_truthy() {
+                    return Ok(lhs);
+                }
+                self.evaluate(*rhs)
+            }
+            (Op::Or, [lhs, rhs]) => {
+                let lhs = self.evaluate(*lhs)?;
+                if lhs.is_truthy() {
+                    return Ok(lhs);
+                }
+                self.evaluate(*rhs)
+            }
+            (_, [lhs, rhs]) => {
+                let lhs = self.evaluate(*lhs)?;
+                let rhs = self.evaluate(*rhs)?;
+                self.binary(op, lhs, rhs, expr)
+            }
+            _ => self.error(&format!("`{op}` can not be evaluated here"), expr),
+        }
+    }
+
+    // Replaces the value of a variable or list element with what `update` makes of it,
+    // returns the old and the new value.
+    fn update(
+        &mut self,
+        target: NodeId,
+        expr: NodeId,
+        update: impl FnOnce(&mut Self, Value) -> Result<Value>,
+    ) -> Result<(Value, Value), miette::Report> {
+        let ast = Rc::clone(&self.ast);
+        match &ast[target] {
+            Tree::NonTerm(Op::Index, place) => {
+                let (list, index) = self.evaluate_index(place[0], place[1], expr)?;
+                let old = list.borrow()[index].clone();
+                let new = update(self, old.clone())?;
+                list.borrow_mut()[index] = new.clone();
+                Ok((old, new))
+            }
+            Tree::Atom(Atom::Ident) => {
+                let Some(old) = self.lookup(target) else {
+                    let msg = format!("undefined variable `{}`", ast.text(target));
+                    return self.error(&msg, target);
+                };
+                let new = update(self, old.clone())?;
+                self.assign(target, new.clone());
+                Ok((old, new))
+            }
+            _ => self.error("invalid assignment target", target),
+        }
+    }
+
+    // Evaluates `list[index]` down to the list and a bounds checked position in it.
+    fn evaluate_index(
+        &mut self,
+        list: NodeId,
+        index: NodeId,
+        expr: NodeId,
+    ) -> Result<(List, usize), miette::Report> {
+        let list = match self.evaluate(list)? {
+            Value::List(x) => x,
+            other => {
+                let msg = format!("a {} can not be indexed", other.type_name());
+                return self.error(&msg, expr);
+            }
+        };
+        let index = match self.evaluate(index)? {
+            Value::Number(x) if x.fract() == 0.0 => x,
+            other => {
+                let msg = format!("list index must be an integer, found `{other}`");
+                return self.error(&msg, expr);
+            }
+        };
+        let len = list.borrow().len();
+        if index < 0.0 || index >= len as f64 {
+            let msg = format!("index {index} is out of bounds for a list of length {len}");
+            return self.error(&msg, expr);
+        }
+        Ok((list, index as usize))
+    }
+
+    fn binary(&mut self, op: &Op, lhs: Value, rhs: Value, expr: NodeId) -> Result<Value> {
+        let output = match (op, &lhs, &rhs) {
+            (Op::EqualEqual, ..) => Value::Bool(lhs == rhs),
+            (Op::BangEqual, ..) => Value::Bool(lhs != rhs),
+            (Op::Plus, Value::String(a), Value::String(b)) => {
+                Value::String(Rc::from(format!("{a}{b}")))
+            }
+            (_, Value::Number(a), Value::Number(b)) => match op {
+                Op::Plus => Value::Number(a + b),
+                Op::Minus => Value::Number(a - b),
+                Op::Star => Value::Number(a * b),
+                Op::Slash => Value::Number(a / b),
+                Op::Percent => Value::Number(a % b),
+                Op::StarStar => Value::Number(a.powf(*b)),
+                Op::Ampersand | Op::Pipe | Op::Caret | Op::LessLess | Op::GreaterGreater => {
+                    Value::Number(self.bitwise(op, *a, *b, expr)? as f64)
+                }
+                Op::Less => Value::Bool(a < b),
+                Op::LessEqual => Value::Bool(a <= b),
+                Op::Greater => Value::Bool(a > b),
+                Op::GreaterEqual => Value::Bool(a >= b),
+                _ => return self.type_error(op, &[lhs, rhs], expr),
+            },
+            _ => return self.type_error(op, &[lhs, rhs], expr),
+        };
+        Ok(output)
+    }
+
+    pub fn call(
+        &mut self,
+        callee: Value,
+        arguments: Vec<Value>,
+        expr: NodeId,
+    ) -> Result<Value, miette::Report> {
+        let Value::Function(function) = callee else {
+            let msg = format!("a {} is not callable", callee.type_name());
+            return self.error(&msg, expr);
+        };
+        if function.parameters.len() != arguments.len() {
+            let msg = format!(
+                "expected {} arguments but got {}",
+                function.parameters.len(),
+                arguments.len()
+            );
+            return self.error(&msg, expr);
+        }
+        if self.depth >= MAX_CALL_DEPTH {
+            return self.error("Stack overflow.", expr);
+        }
+        let env = Rc::new(RefCell::new(Environment::new(Some(Rc::clone(
+            &function.closure,
+        )))));
+        let previous = std::mem::replace(&mut self.ast, Rc::clone(&function.ast));
+        let resolved = std::mem::replace(&mut self.resolution, Rc::clone(&function.resolution));
+        for (name, value) in function.parameters.iter().zip(arguments) {
+            self.define(&env, *name, value);
+        }
+        let body = match &function.ast[function.body] {
+            Tree::NonTerm(Op::Group, ids) => ids.as_slice(),
+            _ => std::slice::from_ref(&function.body),
+        };
+        self.depth += 1;
+        let output = self.execute_block(body, env);
+        self.depth -= 1;
+        self.ast = previous;
+        self.resolution = resolved;
+        match output? {
+            Flow::Return(x) => Ok(x),
+            Flow::Normal => Ok(Value::Nil),
+            // Only reachable in programs that skipped the resolver, e.g. read from a .sexpr file.
+            Flow::Break | Flow::Continue => {
+                self.error("a loop can not be left from a function", expr)
+            }
+        }
+    }
+
+    fn make_function(
+        &self,
+        id: NodeId,
+        name: Option<&str>,
+        parameters: &[NodeId],
+        body: NodeId,
+    ) -> Value {
+        // A function that captures no locals only needs the globals, so it does
+        // not keep the environment it was made in alive.
+        let closure = match self.resolution.captures.get(&id) {
+            Some(x) if x.is_empty() => &self.globals,
+            _ => &self.env,
+        };
+        Value::Function(Rc::new(Function {
+            name: name.map(String::from),
+            parameters: parameters.to_vec(),
+            ast: Rc::clone(&self.ast),
+            resolution: Rc::clone(&self.resolution),
+            body,
+            closure: Rc::clone(closure),
+        }))
+    }
+
+    // Declares the variable the identifier `name` names in `env`, in the slot
+    // the resolver gave it or by name for a global.
+    fn define(&self, env: &Rc<RefCell<Environment>>, name: NodeId, value: Value) {
+        match self.resolution.locals.get(&name) {
+            Some(x) => env.borrow_mut().define_at(x.slot, value),
+            None => env.borrow_mut().define(self.ast.text(name), value),
+        }
+    }
+
+    fn lookup(&self, name: NodeId) -> Option<Value> {
+        match self.resolution.locals.get(&name) {
+            Some(x) => self.env.borrow().get_at(*x),
+            None => self.env.borrow().get(self.ast.text(name)),
+        }
+    }
+
+    fn assign(&self, name: NodeId, value: Value) -> bool {
+        match self.resolution.locals.get(&name) {
+            Some(x) => self.env.borrow_mut().assign_at(*x, value),
+            None => self.env.borrow_mut().assign(self.ast.text(name), value),
+        }
+    }
+
+    // Bitwise operators work on the integer a number holds, a fraction is an error
+    // rather than being truncated.
+    fn bitwise(&self, op: &Op, a: f64, b: f64, expr: NodeId) -> Result<i64> {
+        let mut operands = [0; 2];
+        for (slot, x) in operands.iter_mut().zip([a, b]) {
+            if x.fract() != 0.0 || !(i64::MIN as f64..=i64::MAX as f64).contains(&x) {
+                return self.error(&format!("`{op}` needs integer operands, found {x}"), expr);
+            }
+            *slot = x as i64;
+        }
+        let [a, b] = operands;
+        let output = match op {
+            Op::Ampersand => a & b,
+            Op::Pipe => a | b,
+            Op::Caret => a ^ b,
+            _ => {
+                let Some(shift) = u32::try_from(b).ok().filter(|x| *x < 64) else {
+                    let msg = format!("`{op}` can only shift by 0 to 63 bits, found {b}");
+                    return self.error(&msg, expr);
+                };
+                match op {
+                    Op::LessLess => a << shift,
+                    _ => a >> shift,
+                }
+            }
+        };
+        Ok(output)
+    }
+
+    fn type_error<T>(&self, op: &Op, operands: &[Value], expr: NodeId) -> Result<T> {
+        let types: Vec<&str> = operands.iter().map(Value::type_name).collect();
+        let msg = format!("`{op}` can not be applied to {}", types.join(" and "));
+        self.error(&msg, expr)
+    }
+
+    fn error<T>(&self, message: &str, found: NodeId) -> Result<T, miette::Report> {
+        let range = self.ast.span(found);
+        Err(RuntimeError {
+            source: Arc::clone(&self.ast.source),
+            primary_span: SourceSpan::new(range.start.into(), range.end - range.start),
+        })
+        .wrap_err(String::from(message))
+    }
+}
@@ -0,0 +1,145 @@
+//! Loads a program spread over several files with `import "path";`.
+use crate::analysis::{self, Resolution};
+use crate::parser::{self, Ast, NodeId, Op, Tree};
+use crate::source::Source;
+use crate::trace::Tracer;
+use miette::{Context, Diagnostic, Result, SourceSpan};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::Arc,
+};
+
+/// A loaded file and what the resolver learned about it.
+pub type Loaded = (Rc<Ast>, Rc<Resolution>);
+
+#[derive(Debug, Diagnostic)]
+#[diagnostic(help("an import names a file relative to the file it is in"))]
+pub struct ImportError {
+    #[source_code]
+    source: Arc<Source>,
+    #[label("imported here")]
+    primary_span: SourceSpan,
+}
+
+impl std::error::Error for ImportError {}
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "could not import a file")
+    }
+}
+
+/// Reads, parses and resolves a file and everything it imports.
+///
+/// Each file is keyed by its canonical path, so a file imported from several
+/// places is loaded once and every import of it shares one [`Ast`] and
+/// [`Resolution`], which the interpreter uses to run it only once. An import of a file that is still
+/// being loaded is reported as a cycle. Every [`Ast`] keeps the [`Source`] it
+/// was parsed from, so diagnostics name the file they are about.
+#[derive(Debug, Default)]
+pub struct Loader {
+    //Every file loaded so far, by canonical path
+    files: HashMap<PathBuf, Loaded>,
+    //Canonical and shown paths of the files being loaded, the outermost first
+    loading: Vec<(PathBuf, String)>,
+    pub warn_unassigned: bool,
+    pub warn_unused: bool,
+    pub warn_shadow: bool,
+    pub trace_parse: bool,
+    pub trace_resolve: bool,
+    pub warnings: Vec<miette::Report>,
+}
+
+impl Loader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the program whose main file, already read into `text`, is at `path`.
+    pub fn load(&mut self, path: &Path, text: String) -> Result<Loaded, miette::Report> {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.load_file(path, canonical, text)
+    }
+
+    fn load_file(
+        &mut self,
+        path: &Path,
+        canonical: PathBuf,
+        text: String,
+    ) -> Result<Loaded, miette::Report> {
+        let name = path.display().to_string();
+        let source = Source::new(name.clone(), text);
+        self.loading.push((canonical.clone(), name));
+        let output = self.parse_and_resolve(path, source);
+        self.loading.pop();
+        let loaded = output?;
+        self.files.insert(canonical, loaded.clone());
+        Ok(loaded)
+    }
+
+    fn parse_and_resolve(&mut self, path: &Path, source: Source) -> Result<Loaded, miette::Report> {
+        let mut parser = parser::Parser::new(source)?;
+        if self.trace_parse {
+            parser.trace = Some(Tracer::new("parse"));
+        }
+        let mut ast = parser.parse_program()?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut imports = HashMap::new();
+        for id in ast.program.clone() {
+            if let Tree::NonTerm(Op::Import, children) = &ast[id] {
+                let text = ast.text(children[0]);
+                let target = dir.join(&text[1..text.len() - 1]);
+                let (module, resolution) = self.import(&ast, id, &target)?;
+                ast.imports.insert(id, module);
+                imports.insert(id, resolution);
+            }
+        }
+        let mut resolver = analysis::Resolver::new(parser);
+        resolver.imports = imports;
+        resolver.warn_unassigned = self.warn_unassigned;
+        resolver.warn_unused = self.warn_unused;
+        resolver.warn_shadow = self.warn_shadow;
+        if self.trace_resolve {
+            resolver.trace = Some(Tracer::new("resolve"));
+        }
+        let loaded = resolver.resolve_tree(ast)?;
+        self.warnings.append(&mut resolver.warnings);
+        Ok(loaded)
+    }
+
+    fn import(
+        &mut self,
+        importer: &Ast,
+        id: NodeId,
+        path: &Path,
+    ) -> Result<Loaded, miette::Report> {
+        let error = |message: String| {
+            let range = importer.span(id);
+            Err(ImportError {
+                source: Arc::clone(&importer.source),
+                primary_span: SourceSpan::new(range.start.into(), range.end - range.start),
+            })
+            .wrap_err(message)
+        };
+        let Ok(canonical) = path.canonicalize() else {
+            return error(format!("could not find `{}`", path.display()));
+        };
+        if let Some(loaded) = self.files.get(&canonical) {
+            return Ok(loaded.clone());
+        }
+        if let Some(start) = self.loading.iter().position(|(x, _)| *x == canonical) {
+            let mut cycle: Vec<&str> = self.loading[start..]
+                .iter()
+                .map(|(_, x)| x.as_str())
+                .collect();
+            let name = path.display().to_string();
+            cycle.push(&name);
+            return error(format!("import cycle: {}", cycle.join(" -> ")));
+        }
+        let Ok(text) = std::fs::read_to_string(path) else {
+            return error(format!("could not read `{}`", path.display()));
+        };
+        self.load_file(path, canonical, text)
+    }
+}
@@ -0,0 +1,45 @@
+//! Rewrites syntax sugar into the core forms, so the resolver and the
+//! interpreter only handle those.
+//!
+//! `for (init; cond; inc) body` becomes `{ init; while (cond) body }`, where the
+//! `while` keeps `inc` as its third child, see [`Op::While`]. A missing
+//! condition is `true`, a missing initializer leaves out the block.
+//!
+//! Compound assignment and `++`/`--` are not lowered. `xs[f()] += 1` has to
+//! evaluate `xs` and `f()` once and `x++` gives the old value, neither can be
+//! written with `=` without a temporary, which no core form provides, so the
+//! resolver and the interpreter still handle those operators themselves.
+use crate::parser::{Ast, Atom, NodeId, Op, Tree};
+use crate::visit::{self, Folder};
+
+pub fn lower(mut ast: Ast) -> Ast {
+    let ids = std::mem::take(&mut ast.program);
+    ast.program = visit::fold_ids(&mut Lower, &mut ast, ids);
+    ast
+}
+
+struct Lower;
+
+impl Folder for Lower {
+    fn fold_non_term(&mut self, ast: &mut Ast, id: NodeId, op: Op, ids: Vec<NodeId>) -> NodeId {
+        let ids = visit::fold_ids(self, ast, ids);
+        let (Op::For, &[init, cond, inc, body]) = (op, &ids[..]) else {
+            ast.replace(id, Tree::NonTerm(op, ids));
+            return id;
+        };
+        let cond = match ast[cond] {
+            Tree::Nil => ast.push(Tree::Atom(Atom::Bool(true)), ast.span(cond)),
+            _ => cond,
+        };
+        let mut looping = vec![cond, body];
+        if ast[inc] != Tree::Nil {
+            looping.push(inc);
+        }
+        let looping = ast.push(Tree::NonTerm(Op::While, looping), ast.span(id));
+        if ast[init] == Tree::Nil {
+            return looping;
+        }
+        ast.replace(id, Tree::NonTerm(Op::Group, vec![init, looping]));
+        id
+    }
+}
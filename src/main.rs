@@ -1,25 +1,93 @@
-use crate::analysis::Visitor;
-use std::{ffi::OsString, fs};
+use std::{ffi::OsString, fs, rc::Rc};
 mod analysis;
+mod ast_json;
+mod format;
+// An API for editors that embed the parser, the command line has no use for it.
+#[allow(dead_code)]
+mod incremental;
+mod interpreter;
+mod loader;
+mod lower;
 mod parser;
 mod scanner;
+mod sexpr;
+mod source;
+#[cfg(test)]
 mod test;
+mod trace;
+mod visit;
 mod vm;
 struct Args {
     file: Option<OsString>,
+    warn_unassigned: bool,
+    warn_unused: bool,
+    warn_shadow: bool,
+    //`rlox fmt [--check] FILE`
+    fmt: bool,
+    check: bool,
+    //`--emit=KIND` prints the parsed program instead of running it
+    emit: Option<Emit>,
+    //`--trace=parse,resolve` writes what those passes do to stderr
+    trace_parse: bool,
+    trace_resolve: bool,
+}
+enum Emit {
+    Sexpr,
+    AstJson,
 }
 fn parse_args() -> Result<Args, lexopt::Error> {
     use lexopt::prelude::*;
     let mut file = None;
+    let mut warn_unassigned = false;
+    let mut warn_unused = false;
+    let mut warn_shadow = false;
+    let mut fmt = false;
+    let mut check = false;
+    let mut emit = None;
+    let mut trace_parse = false;
+    let mut trace_resolve = false;
     let mut parser = lexopt::Parser::from_env();
     while let Some(arg) = parser.next()? {
         match arg {
+            Value(val) if file.is_none() && !fmt && val == "fmt" => {
+                fmt = true;
+            }
             Value(val) if file.is_none() => {
                 file = Some(val);
             }
+            Long("warn-unassigned") => {
+                warn_unassigned = true;
+            }
+            Long("warn-unused") => {
+                warn_unused = true;
+            }
+            Long("warn-shadow") => {
+                warn_shadow = true;
+            }
+            Long("check") => {
+                check = true;
+            }
+            Long("emit") => match parser.value()?.to_str() {
+                Some("sexpr") => emit = Some(Emit::Sexpr),
+                Some("ast-json") => emit = Some(Emit::AstJson),
+                _ => return Err(lexopt::Error::from("--emit expects sexpr or ast-json")),
+            },
+            Long("trace") => {
+                for pass in parser.value()?.to_string_lossy().split(',') {
+                    match pass {
+                        "parse" => trace_parse = true,
+                        "resolve" => trace_resolve = true,
+                        _ => {
+                            return Err(lexopt::Error::from(
+                                "--trace expects parse, resolve or both",
+                            ));
+                        }
+                    }
+                }
+            }
             Long("help") => {
                 println!(
-                    "Usage: provide a file-path as the first pos arg \nExample: rlox main.lox"
+                    "Usage: provide a file-path as the first pos arg \nExample: rlox main.lox\n\n--warn-unassigned  warn when a variable declared without a value is never assigned\n--warn-unused      warn about locals never read, parameters never used and top level functions never called\n--warn-shadow      warn when a declaration shadows one of an enclosing scope\n--emit=sexpr       print the syntax tree as S-expressions instead of running it\n--emit=ast-json    print the syntax tree as JSON instead of running it\n--trace=PASSES     write what parse, resolve or both do to stderr, as in --trace=parse,resolve\n\nfiles ending in .sexpr hold a syntax tree in that form and are run directly\n\nrlox fmt [--check] main.lox\n  rewrites the file in the canonical style, --check only reports files that are not formatted"
                 );
             }
             _ => return Err(arg.unexpected()),
@@ -27,17 +95,82 @@ fn parse_args() -> Result<Args, lexopt::Error> {
     }
     Ok(Args {
         file: file.or_else(|| None),
+        warn_unassigned,
+        warn_unused,
+        warn_shadow,
+        fmt,
+        check,
+        emit,
+        trace_parse,
+        trace_resolve,
     })
 }
 
+fn fmt(path: OsString, check: bool) -> Result<(), miette::Report> {
+    let name = path.to_string_lossy();
+    let contents = match fs::read_to_string(&path) {
+        Ok(r) => r,
+        Err(_) => return Err(miette::miette!("could not read {name}")),
+    };
+    let formatted = format::format(source::Source::new(name.clone(), contents.clone()))?;
+    if formatted == contents {
+        return Ok(());
+    }
+    if check {
+        return Err(miette::miette!("{name} is not formatted"));
+    }
+    if fs::write(&path, formatted).is_err() {
+        return Err(miette::miette!("could not write {name}"));
+    }
+    Ok(())
+}
+
 fn hello(args: Args) -> Result<(), miette::Report> {
-    if args.file.is_some() {
-        let contents = match fs::read_to_string(args.file.unwrap()) {
+    if args.fmt {
+        match args.file {
+            Some(path) => fmt(path, args.check)?,
+            None => eprintln!("Must Provide a pos argument: rlox fmt PATH_TO_FILE"),
+        }
+    } else if let Some(path) = args.file {
+        let path = std::path::Path::new(&path);
+        let name = path.display().to_string();
+        let contents = match fs::read_to_string(path) {
             Ok(r) => r,
-            Err(_) => return Err(miette::miette!("main io error")),
+            Err(_) => return Err(miette::miette!("could not read {name}")),
         };
-        let mut scope = analysis::Resolver::new(parser::Parser::new(contents)?);
-        scope.resolve()?;
+        if path.extension() == Some("sexpr".as_ref()) {
+            let program = sexpr::read(source::Source::new(name, contents))?;
+            let program = Rc::new(lower::lower(program));
+            return interpreter::Interpreter::new().interpret(&program, &Rc::default());
+        }
+        if let Some(emit) = args.emit {
+            let source = source::Source::new(name, contents);
+            let mut parser = parser::Parser::new(source)?;
+            if args.trace_parse {
+                parser.trace = Some(trace::Tracer::new("parse"));
+            }
+            let program = parser.parse_program()?;
+            match emit {
+                Emit::Sexpr => {
+                    for stmt in &program.program {
+                        println!("{}", program.sexpr(*stmt));
+                    }
+                }
+                Emit::AstJson => println!("{}", ast_json::to_json(&program)),
+            }
+            return Ok(());
+        }
+        let mut loader = loader::Loader::new();
+        loader.warn_unassigned = args.warn_unassigned;
+        loader.warn_unused = args.warn_unused;
+        loader.warn_shadow = args.warn_shadow;
+        loader.trace_parse = args.trace_parse;
+        loader.trace_resolve = args.trace_resolve;
+        let (program, resolution) = loader.load(path, contents)?;
+        for warning in &loader.warnings {
+            eprintln!("{:?}", warning);
+        }
+        interpreter::Interpreter::new().interpret(&program, &resolution)?;
     } else {
         eprintln!("Must Provide a pos argument: rlox PATH_TO_FILE");
     }
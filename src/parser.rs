@@ -1,7 +1,9 @@
 //TODO: Parse expresion statments properly
 use crate::scanner::{self, Token, TokenType};
-use core::panic;
+use crate::source::Source;
+use crate::trace::Tracer;
 use miette::{Context, Diagnostic, Result, SourceSpan};
+use std::collections::HashMap;
 use std::ops::Range;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -10,7 +12,7 @@ use std::sync::Arc;
 #[diagnostic(help("this is a syntax error"))]
 pub struct ParserError {
     #[source_code]
-    source: Arc<String>,
+    source: Arc<Source>,
     #[label("main issue")]
     primary_span: SourceSpan,
 }
@@ -28,35 +30,114 @@ pub fn token_to_span(token: &Token) -> SourceSpan {
     )
 }
 
-#[derive(Debug)]
+/// The most parameters a function may declare, matching the Lox reference.
+pub const MAX_PARAMETERS: usize = 255;
+
+/// How deeply statements and expressions may nest before the parser gives up,
+/// keeps hostile input like `((((...` from overflowing the stack.
+pub const MAX_DEPTH: usize = 256;
+
+/// Index of a node in the [`Ast`] it was parsed into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(u32);
+
+impl NodeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// A parsed program. Every node lives in one arena and refers to its children
+/// by [`NodeId`], so passes can keep what they learn about a node in a side
+/// table indexed by [`NodeId::index`], the way the spans are kept here.
+#[derive(Debug, Clone)]
+pub struct Ast {
+    pub source: Arc<Source>,
+    /// The top level statements, in source order.
+    pub program: Vec<NodeId>,
+    nodes: Vec<Tree>,
+    //Byte offsets as u32, a source over 4 GiB is not supported
+    spans: Vec<Range<u32>>,
+    /// The file each top level `import` loaded, filled in by [`crate::loader::Loader`].
+    pub imports: HashMap<NodeId, Rc<Ast>>,
+}
+
+impl Ast {
+    pub fn new(source: Arc<Source>) -> Self {
+        Ast {
+            source,
+            program: Vec::new(),
+            nodes: Vec::new(),
+            spans: Vec::new(),
+            imports: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, tree: Tree, span: Range<usize>) -> NodeId {
+        let id = NodeId(self.nodes.len() as u32);
+        self.nodes.push(tree);
+        self.spans.push(span.start as u32..span.end as u32);
+        id
+    }
+
+    /// Swaps the node stored at `id`, its span stays the same.
+    pub fn replace(&mut self, id: NodeId, tree: Tree) {
+        self.nodes[id.index()] = tree;
+    }
+
+    /// The byte range of the source a node was parsed from.
+    pub fn span(&self, id: NodeId) -> Range<usize> {
+        let span = &self.spans[id.index()];
+        span.start as usize..span.end as usize
+    }
+
+    /// The source text of a node, the name of an identifier or a string with its quotes.
+    pub fn text(&self, id: NodeId) -> &str {
+        &self.source.text[self.span(id)]
+    }
+
+    /// Writes the subtree at `id` as an S-expression.
+    pub fn sexpr(&self, id: NodeId) -> Sexpr<'_> {
+        Sexpr { ast: self, id }
+    }
+}
+
+impl std::ops::Index<NodeId> for Ast {
+    type Output = Tree;
+    fn index(&self, id: NodeId) -> &Tree {
+        &self.nodes[id.index()]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Tree {
     Nil,
-    ExprStatment(Vec<Tree>),
-    Var(String, Rc<Tree>),
+    ExprStatment(Vec<NodeId>),
+    /// The name is an identifier node.
+    Var(NodeId, Option<NodeId>),
     Call {
-        callee: Rc<Tree>,
-        arguments: Vec<Tree>,
+        callee: NodeId,
+        arguments: Vec<NodeId>,
     },
     Atom(Atom),
-    NonTerm(Op, Vec<Tree>),
+    NonTerm(Op, Vec<NodeId>),
     Op(Op),
     Fun {
-        name: Rc<Tree>,
-        parameters: Vec<Rc<Tree>>,
-        body: Rc<Tree>,
+        name: NodeId,
+        parameters: Vec<NodeId>,
+        body: NodeId,
+    },
+    Lambda {
+        parameters: Vec<NodeId>,
+        body: NodeId,
     },
 }
 
+/// Identifiers and strings take their text from the node's span, see [`Ast::text`].
 #[derive(Debug, Clone, PartialEq)]
 pub enum Atom {
-    Ident {
-        range: Range<usize>,
-        source: Arc<String>,
-    },
-    String {
-        range: Range<usize>,
-        source: Arc<String>,
-    },
+    Ident,
+    String,
     Number(f64),
     Nil,
     Bool(bool),
@@ -65,13 +146,16 @@ pub enum Atom {
     Error,
 }
 
-impl Into<Op> for TokenType {
-    fn into(self) -> Op {
-        match self {
+impl TryFrom<TokenType> for Op {
+    type Error = TokenType;
+    fn try_from(kind: TokenType) -> Result<Op, TokenType> {
+        let op = match kind {
             TokenType::LeftBrace => Op::Group,
             TokenType::Minus => Op::Minus,
             TokenType::Plus => Op::Plus,
             TokenType::LeftParen => Op::Call,
+            TokenType::LeftHardBrace => Op::Index,
+            TokenType::Dot => Op::Field,
             TokenType::Slash => Op::Slash,
             TokenType::Star => Op::Star,
             TokenType::Bang => Op::Bang,
@@ -91,8 +175,26 @@ impl Into<Op> for TokenType {
             TokenType::While => Op::While,
             TokenType::If => Op::If,
             TokenType::Else => Op::Else,
-            _ => unreachable!(),
-        }
+            TokenType::PlusEqual => Op::PlusEqual,
+            TokenType::MinusEqual => Op::MinusEqual,
+            TokenType::StarEqual => Op::StarEqual,
+            TokenType::SlashEqual => Op::SlashEqual,
+            TokenType::PercentEqual => Op::PercentEqual,
+            TokenType::PlusPlus => Op::PlusPlus,
+            TokenType::MinusMinus => Op::MinusMinus,
+            TokenType::Percent => Op::Percent,
+            TokenType::StarStar => Op::StarStar,
+            TokenType::Ampersand => Op::Ampersand,
+            TokenType::Pipe => Op::Pipe,
+            TokenType::Caret => Op::Caret,
+            TokenType::LessLess => Op::LessLess,
+            TokenType::GreaterGreater => Op::GreaterGreater,
+            TokenType::Import => Op::Import,
+            TokenType::Break => Op::Break,
+            TokenType::Continue => Op::Continue,
+            other => return Err(other),
+        };
+        Ok(op)
     }
 }
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -119,100 +221,142 @@ pub enum Op {
     Return,
     Field,
     Var,
+    /// `[cond, body]` as parsed. A core form with an optional third child,
+    /// the increment of a lowered `for`, which runs after the body and after
+    /// a `continue`. Every pass after lowering handles both forms.
     While,
     Group,
     Equal,
+    List,
+    Index,
+    Break,
+    Continue,
+    Percent,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    PercentEqual,
+    //Prefix `++x` and `--x`, the new value is the result
+    PlusPlus,
+    MinusMinus,
+    //Postfix `x++` and `x--`, the old value is the result
+    PostPlusPlus,
+    PostMinusMinus,
+    Import,
+    StarStar,
+    //Bitwise, on numbers that are integers
+    Ampersand,
+    Pipe,
+    Caret,
+    LessLess,
+    GreaterGreater,
 }
 
 #[derive(Debug)]
 pub struct Parser {
     pub stream: Vec<Token>,
-    pub input: Arc<String>,
+    pub input: Arc<Source>,
     pub pos: usize,
+    pub depth: usize,
+    pub ast: Ast,
+    //Set by `--trace=parse`
+    pub trace: Option<Tracer>,
 }
 
 impl Parser {
-    pub fn new(input: String) -> Result<Parser, miette::Report> {
+    /// Scans `input` for parsing, diagnostics are reported against its name.
+    pub fn new(input: impl Into<Source>) -> Result<Parser, miette::Report> {
+        let input = Arc::new(input.into());
         let stream = scanner::collect(&input)?;
-        Ok(Parser {
+        Ok(Parser::with_tokens(input, stream))
+    }
+
+    /// Parses a `stream` already scanned from `input`.
+    pub fn with_tokens(input: Arc<Source>, stream: Vec<Token>) -> Parser {
+        let mut ast = Ast::new(Arc::clone(&input));
+        // There are about as many nodes as tokens, so the arena rarely has to grow.
+        ast.nodes.reserve(stream.len());
+        ast.spans.reserve(stream.len());
+        Parser {
             stream,
-            input: Arc::new(input),
+            ast,
+            input,
             pos: 0,
-        })
+            depth: 0,
+            trace: None,
+        }
     }
 
-    pub fn parse_program(&mut self) -> Result<Vec<Tree>, miette::Report> {
-        let mut parent = Vec::new();
-        while self.pos <= self.stream.len() {
+    pub fn parse_program(&mut self) -> Result<Ast, miette::Report> {
+        while !self.expect(TokenType::Eof) {
             let val = self.parse_statment()?;
-            match val {
-                Tree::Atom(x) => match x {
-                    Atom::Nil => {
-                        break;
-                    }
-                    _ => continue,
-                },
-                x => parent.push(x),
+            if !matches!(self.ast[val], Tree::Atom(_)) {
+                self.ast.program.push(val);
             }
         }
-        Ok(parent)
+        Ok(self.take_ast())
     }
 
-    pub fn parse_expr(&mut self, min_bp: u8) -> Result<Tree, miette::Report> {
-        let mut lhs = match self.advance() {
-            Token {
-                kind: TokenType::String,
-                range: Range { start, end },
-            } => Tree::Atom(Atom::String {
-                range: Range { start, end },
-                source: Arc::clone(&self.input),
-            }),
-            Token {
-                kind: TokenType::Number(n),
-                ..
-            } => Tree::Atom(Atom::Number(n)),
-            Token {
-                kind: TokenType::True,
-                ..
-            } => Tree::Atom(Atom::Bool(true)),
-            Token {
-                kind: TokenType::False,
-                ..
-            } => Tree::Atom(Atom::Bool(false)),
-            Token {
-                kind: TokenType::Nil,
-                ..
-            } => Tree::Atom(Atom::Nil),
-            Token {
-                kind: TokenType::Identifier,
-                range: Range { start, end },
-            } => Tree::Atom(Atom::Ident {
-                range: Range { start, end },
-                source: Arc::clone(&self.input),
-            }),
-            n @ Token {
-                kind: TokenType::Minus | TokenType::Print | TokenType::Bang,
-                ..
-            } => {
-                let ((), bp) = prefix_binding_power(n.kind);
-                let rhs = self.parse_expr(bp)?;
-                println!("{}", rhs);
-                Tree::NonTerm(n.kind.into(), vec![rhs])
-            }
-            Token {
-                kind: TokenType::LeftParen | TokenType::LeftBrace,
-                ..
-            } => {
-                let lhs = self.parse_expr(0)?;
-                if self.expect(TokenType::RightParen) | self.expect(TokenType::RightBrace) {
-                    return self.error("Expected either ) or } ");
-                };
-                Tree::NonTerm(Op::Group, vec![lhs])
-            }
-            _ => {
-                println!("{:?}", self.peek());
-                return self.error("Expected expresion");
-            }
+    /// Hands out the tree parsed so far and starts an empty one.
+    pub fn take_ast(&mut self) -> Ast {
+        let fresh = Ast::new(Arc::clone(&self.input));
+        let mut ast = std::mem::replace(&mut self.ast, fresh);
+        // Growing by doubling can leave the arena up to half empty.
+        ast.nodes.shrink_to_fit();
+        ast.spans.shrink_to_fit();
+        ast
+    }
+
+    // Adds a node spanning from `start` to the end of the last consumed token.
+    fn push(&mut self, start: usize, tree: Tree) -> NodeId {
+        let end = self.current().range.end.max(start);
+        self.ast.push(tree, start..end)
+    }
+
+    pub fn parse_expr(&mut self, min_bp: u8) -> Result<NodeId, miette::Report> {
+        self.nested("expression", |parser| parser.parse_expr_inner(min_bp))
+    }
+
+    // Runs one level of recursive descent, failing once MAX_DEPTH levels are open.
+    fn nested(
+        &mut self,
+        what: &str,
+        parse: impl FnOnce(&mut Self) -> Result<NodeId, miette::Report>,
+    ) -> Result<NodeId, miette::Report> {
+        if self.depth >= MAX_DEPTH {
+            let token = self.peek();
+            return self.error_at(&token, "Too deeply nested");
+        }
+        if let Some(trace) = &mut self.trace {
+            let at = self.stream.get(self.pos).map_or(0, |x| x.range.start);
+            trace.enter(what, &self.input, at);
+        }
+        self.depth += 1;
+        let output = parse(self);
+        self.depth -= 1;
+        if let Some(trace) = &mut self.trace {
+            trace.exit(what, output.as_ref().ok().map(|x| (&self.ast, *x)));
+        }
+        output
+    }
+
+    fn parse_expr_inner(&mut self, min_bp: u8) -> Result<NodeId, miette::Report> {
+        let first = self.advance();
+        let atom = match first.kind {
+            TokenType::String => Some(Atom::String),
+            TokenType::Number(n) => Some(Atom::Number(n)),
+            TokenType::True => Some(Atom::Bool(true)),
+            TokenType::False => Some(Atom::Bool(false)),
+            TokenType::Nil => Some(Atom::Nil),
+            TokenType::Identifier => Some(Atom::Ident),
+            TokenType::This => Some(Atom::This),
+            TokenType::Super => Some(Atom::Super),
+            _ => None,
+        };
+        let mut lhs = match atom {
+            Some(atom) => self.ast.push(Tree::Atom(atom), first.range),
+            None => self.parse_prefix(first)?,
         };
 
         loop {
@@ -222,6 +366,7 @@ impl Parser {
                         TokenType::Eof
                         | TokenType::RightParen
                         | TokenType::RightBrace
+                        | TokenType::RightHardBrace
                         | TokenType::Comma
                         | TokenType::Semicolon,
                     ..
@@ -234,6 +379,7 @@ impl Parser {
                         | TokenType::LeftParen
                         | TokenType::LeftBrace
                         | TokenType::LeftHardBrace
+                        | TokenType::Dot
                         | TokenType::BangEqual
                         | TokenType::EqualEqual
                         | TokenType::Minus
@@ -243,7 +389,23 @@ impl Parser {
                         | TokenType::LessEqual
                         | TokenType::Less
                         | TokenType::Slash
-                        | TokenType::Star,
+                        | TokenType::Star
+                        | TokenType::PlusEqual
+                        | TokenType::MinusEqual
+                        | TokenType::StarEqual
+                        | TokenType::SlashEqual
+                        | TokenType::PercentEqual
+                        | TokenType::PlusPlus
+                        | TokenType::MinusMinus
+                        | TokenType::Percent
+                        | TokenType::StarStar
+                        | TokenType::Ampersand
+                        | TokenType::Pipe
+                        | TokenType::Caret
+                        | TokenType::LessLess
+                        | TokenType::GreaterGreater
+                        | TokenType::And
+                        | TokenType::Or,
                     ..
                 } => n,
                 _ => {
@@ -255,38 +417,45 @@ impl Parser {
                     break;
                 }
                 self.advance();
-                lhs = if op.kind == TokenType::LeftParen {
+                let start = self.ast.span(lhs).start;
+                let tree = if op.kind == TokenType::LeftParen {
                     let mut temp = Vec::new();
-                    let rhs = if self.expect(TokenType::RightParen) {
-                        temp
-                    } else {
-                        loop {
-                            if self.expect(TokenType::RightParen) {
-                                break temp;
-                            }
-                            let range = self.current().range;
-                            let attempt: Tree = self.parse_expr(0).unwrap_or_else(|_| {
-                                self.advance();
-                                Tree::Atom(Atom::Ident {
-                                    range,
-                                    source: Arc::clone(&self.input),
-                                })
-                            });
-                            if self.expect(TokenType::Comma) {
-                                self.advance();
-                            }
-                            println!("pushed: {}", attempt);
-                            temp.push(attempt);
+                    let rhs = loop {
+                        if self.expect(TokenType::RightParen) {
+                            break temp;
+                        }
+                        let attempt = self.parse_expr(0)?;
+                        if self.expect(TokenType::Comma) {
+                            self.advance();
+                        } else if !self.expect(TokenType::RightParen) {
+                            return self.error("Expected , or ) in arguments");
                         }
+                        temp.push(attempt);
                     };
                     self.advance();
                     Tree::Call {
-                        callee: Rc::new(lhs),
+                        callee: lhs,
                         arguments: rhs,
                     }
+                } else if op.kind == TokenType::LeftHardBrace {
+                    let index = self.parse_expr(0)?;
+                    if !self.expect(TokenType::RightHardBrace) {
+                        return self.error("Expected ] after index");
+                    };
+                    self.advance();
+                    Tree::NonTerm(Op::Index, vec![lhs, index])
                 } else {
-                    Tree::NonTerm(op.kind.into(), vec![lhs])
+                    let op = match self.to_op(&op)? {
+                        Op::PlusPlus => Op::PostPlusPlus,
+                        Op::MinusMinus => Op::PostMinusMinus,
+                        x => x,
+                    };
+                    if matches!(op, Op::PostPlusPlus | Op::PostMinusMinus) {
+                        self.check_target(lhs)?;
+                    }
+                    Tree::NonTerm(op, vec![lhs])
                 };
+                lhs = self.push(start, tree);
                 continue;
             }
             if let Some((l_bp, r_bp)) = infix_binding_power(op.kind) {
@@ -294,38 +463,144 @@ impl Parser {
                     break;
                 }
                 self.advance();
+                let start = self.ast.span(lhs).start;
+                let op = self.to_op(&op)?;
+                if op.is_compound_assignment() {
+                    self.check_target(lhs)?;
+                }
+                if op == Op::Field && !self.expect(TokenType::Identifier) {
+                    return self.error("Expected a property name after .");
+                }
                 let rhs = self.parse_expr(r_bp)?;
-                lhs = Tree::NonTerm(op.kind.into(), vec![lhs, rhs]);
+                let tree = Tree::NonTerm(op, vec![lhs, rhs]);
+                lhs = self.push(start, tree);
                 continue;
             }
             break;
         }
         Ok(lhs)
     }
-    fn error(&mut self, msg_input: &str) -> Result<Tree, miette::Report> {
+    // An expression that starts with `first` and is not an atom.
+    fn parse_prefix(&mut self, first: Token) -> Result<NodeId, miette::Report> {
+        let start = first.range.start;
+        match first {
+            n @ Token {
+                kind:
+                    TokenType::Minus | TokenType::Bang | TokenType::PlusPlus | TokenType::MinusMinus,
+                ..
+            } => {
+                let Some(((), bp)) = prefix_binding_power(n.kind) else {
+                    return self.error_at(&n, "Expected a prefix operator");
+                };
+                let op = self.to_op(&n)?;
+                let rhs = self.parse_expr(bp)?;
+                if matches!(op, Op::PlusPlus | Op::MinusMinus) {
+                    self.check_target(rhs)?;
+                }
+                Ok(self.push(start, Tree::NonTerm(op, vec![rhs])))
+            }
+            Token {
+                kind: TokenType::LeftHardBrace,
+                ..
+            } => {
+                let mut items = Vec::new();
+                while !self.expect(TokenType::RightHardBrace) {
+                    items.push(self.parse_expr(0)?);
+                    if self.expect(TokenType::Comma) {
+                        self.advance();
+                    } else if !self.expect(TokenType::RightHardBrace) {
+                        return self.error("Expected , or ] in list");
+                    }
+                }
+                self.advance();
+                Ok(self.push(start, Tree::NonTerm(Op::List, items)))
+            }
+            Token {
+                kind: TokenType::Fun,
+                ..
+            } => {
+                let parameters = self.parse_parameters()?;
+                if !self.expect(TokenType::LeftBrace) {
+                    return self.error("Expected { before function body");
+                };
+                let body = self.parse_statment()?;
+                Ok(self.push(start, Tree::Lambda { parameters, body }))
+            }
+            n @ Token {
+                kind: TokenType::LeftParen | TokenType::LeftBrace,
+                ..
+            } => {
+                let lhs = self.parse_expr(0)?;
+                let close = if n.kind == TokenType::LeftParen {
+                    TokenType::RightParen
+                } else {
+                    TokenType::RightBrace
+                };
+                if !self.expect(close) {
+                    return self.error("Expected either ) or } ");
+                };
+                self.advance();
+                Ok(self.push(start, Tree::NonTerm(Op::Group, vec![lhs])))
+            }
+            n => self.error_at(&n, "Expected expresion"),
+        }
+    }
+    fn error<T>(&mut self, msg_input: &str) -> Result<T, miette::Report> {
+        let token = self.current();
+        self.error_at(&token, msg_input)
+    }
+    fn error_at<T>(&self, token: &Token, msg_input: &str) -> Result<T, miette::Report> {
         let msg = format!("{}", msg_input);
         return Err(ParserError {
             source: Arc::clone(&self.input),
-            primary_span: token_to_span(&self.current()),
+            primary_span: token_to_span(token),
         })
         .wrap_err(msg);
     }
+    // Only a variable or a list element can be updated in place.
+    fn check_target(&self, target: NodeId) -> Result<(), miette::Report> {
+        match &self.ast[target] {
+            Tree::Atom(Atom::Ident) | Tree::NonTerm(Op::Index | Op::Field, _) => Ok(()),
+            _ => {
+                let range = self.ast.span(target);
+                Err(ParserError {
+                    source: Arc::clone(&self.input),
+                    primary_span: SourceSpan::new(range.start.into(), range.end - range.start),
+                })
+                .wrap_err("Invalid assignment target")
+            }
+        }
+    }
+    fn to_op(&self, token: &Token) -> Result<Op, miette::Report> {
+        Op::try_from(token.kind).or_else(|_| self.error_at(token, "Expected an operator"))
+    }
+
+    // Never moves past the trailing EOF, so peek/current always have a token to hand out.
     pub fn advance(&mut self) -> Token {
-        let output = self.stream.get(self.pos).expect("Invariant broken: should not be possible for advance to return none since the prev match should break out on EOF.").clone();
-        self.pos += 1;
+        let output = self.peek();
+        if self.pos < self.stream.len() - 1 {
+            self.pos += 1;
+        }
         output
     }
 
     pub fn current(&mut self) -> Token {
-        self.stream
-            .get(self.pos - 1)
-            .expect("Invariant broken: if current reached None that means that is bad.")
-            .clone()
+        self.token_at(self.pos.saturating_sub(1))
     }
 
     // behave like peek()
     pub fn peek(&mut self) -> Token {
-        self.stream.get(self.pos).expect("Invariant broken: if peek reached None that means that the prev token must have been EOF and was not caught.").clone()
+        self.token_at(self.pos)
+    }
+
+    fn token_at(&self, pos: usize) -> Token {
+        match self.stream.get(pos).or(self.stream.last()) {
+            Some(x) => x.clone(),
+            None => Token {
+                kind: TokenType::Eof,
+                range: Range { start: 0, end: 0 },
+            },
+        }
     }
 
     fn expect(&mut self, input: TokenType) -> bool {
@@ -344,61 +619,102 @@ impl Parser {
         }
     }
 
-    pub fn parse_statment(&mut self) -> Result<Tree, miette::Report> {
+    // An optional expression followed by `end`, a missing one is a `Nil` node.
+    fn parse_for_clause(&mut self, end: TokenType) -> Result<NodeId, miette::Report> {
+        let clause = if self.expect(end) {
+            let start = self.peek().range.start;
+            self.ast.push(Tree::Nil, start..start)
+        } else {
+            self.parse_expr(0)?
+        };
+        if !self.expect(end) {
+            return match end {
+                TokenType::Semicolon => self.error("Expected a ;"),
+                _ => self.error("Expected a )"),
+            };
+        }
+        self.advance();
+        Ok(clause)
+    }
+
+    /// Parses `(a, b, c)`: plain identifiers separated by commas, at most
+    /// [`MAX_PARAMETERS`] of them, no duplicates and no trailing comma.
+    fn parse_parameters(&mut self) -> Result<Vec<NodeId>, miette::Report> {
+        if !self.expect(TokenType::LeftParen) {
+            return self.error("Expected (");
+        };
+        self.advance();
+        let mut parameters: Vec<NodeId> = Vec::new();
+        if self.expect(TokenType::RightParen) {
+            self.advance();
+            return Ok(parameters);
+        }
+        loop {
+            let param = self.peek();
+            if param.kind != TokenType::Identifier {
+                return self.error_at(&param, "Expected parameter name");
+            }
+            self.advance();
+            let name = &self.input.text[param.range.clone()];
+            let duplicate = parameters.iter().any(|p| self.ast.text(*p) == name);
+            if duplicate {
+                return self.error_at(&param, "Duplicate parameter name");
+            }
+            if parameters.len() == MAX_PARAMETERS {
+                return self.error_at(&param, "Can't have more than 255 parameters");
+            }
+            parameters.push(self.ast.push(Tree::Atom(Atom::Ident), param.range));
+            let next = self.peek();
+            match next.kind {
+                TokenType::Comma => {
+                    self.advance();
+                    if self.expect(TokenType::RightParen) {
+                        return self
+                            .error_at(&next, "Trailing comma is not allowed in a parameter list");
+                    }
+                }
+                TokenType::RightParen => {
+                    self.advance();
+                    break;
+                }
+                _ => return self.error_at(&next, "Expected , or ) after parameter"),
+            }
+        }
+        Ok(parameters)
+    }
+
+    pub fn parse_statment(&mut self) -> Result<NodeId, miette::Report> {
+        self.nested("statement", Self::parse_statment_inner)
+    }
+
+    fn parse_statment_inner(&mut self) -> Result<NodeId, miette::Report> {
+        let start = self.peek().range.start;
         let lhs = match self.peek() {
             Token {
                 kind: TokenType::Fun,
                 ..
             } => {
+                // `fun (a) { .. }` at the start of a statement is a lambda expression.
+                if self.token_at(self.pos + 1).kind == TokenType::LeftParen {
+                    return self.parse_expr_statment();
+                }
                 self.advance();
                 if !self.expect(TokenType::Identifier) {
                     return self.error("Expected function name");
                 };
 
                 let range = self.advance().range;
-                let func_iden = Tree::Atom(Atom::Ident {
-                    range,
-                    source: Arc::clone(&self.input),
-                });
+                let name = self.ast.push(Tree::Atom(Atom::Ident), range);
 
-                let mut temp = Vec::new();
-                if !self.expect(TokenType::LeftParen) {
-                    return self.error("Expected (");
-                };
-                self.advance();
-                loop {
-                    if self.expect(TokenType::RightParen) {
-                        break;
-                    }
-                    if self.expect(TokenType::Comma) {
-                        self.advance();
-                    }
-                    let range = self.current().range;
-                    let attempt: Tree = self.parse_expr(0).unwrap_or_else(|_| {
-                        self.advance();
-                        Tree::Atom(Atom::Ident {
-                            range,
-                            source: Arc::clone(&self.input),
-                        })
-                    });
-                    temp.push(Rc::new(attempt));
+                let parameters = self.parse_parameters()?;
+                let body = self.parse_statment()?;
+                Tree::Fun {
+                    name,
+                    parameters,
+                    body,
                 }
-                if !self.expect(TokenType::RightParen) {
-                    return self.error("Expected )");
-                };
-                self.advance();
-                let block = self.parse_statment()?;
-                return Ok(Tree::Fun {
-                    name: Rc::new(func_iden),
-                    parameters: temp,
-                    body: Rc::new(block),
-                });
             }
 
-            Token {
-                kind: TokenType::Super,
-                ..
-            } => todo!("parse super"),
             Token {
                 kind: TokenType::Var,
                 ..
@@ -408,18 +724,76 @@ impl Parser {
                     return self.error("You must add a identifier after var");
                 };
                 let iden = self.advance();
+                let name = self.ast.push(Tree::Atom(Atom::Ident), iden.range);
+                if self.expect_semicolon() {
+                    // `var a;` declares `a` without a value, it reads as nil.
+                    self.advance();
+                    return Ok(self.push(start, Tree::Var(name, None)));
+                }
                 if !self.expect(TokenType::Equal) {
-                    return self.error("You must add a = after iden");
+                    return self.error("You must add a = or ; after iden");
                 };
                 self.advance();
                 let val = self.parse_expr(0)?;
                 if !self.expect_semicolon() {
                     return self.error("Expected ;");
                 };
-                let name = String::from(&self.input[iden.range.start..iden.range.end]);
                 self.advance();
-                let temp = Rc::new(val);
-                Tree::Var(name, temp)
+                Tree::Var(name, Some(val))
+            }
+            Token {
+                kind: TokenType::Print,
+                ..
+            } => {
+                self.advance();
+                let val = self.parse_expr(0)?;
+                if !self.expect_semicolon() {
+                    return self.error("Expected ; after print statement");
+                };
+                self.advance();
+                Tree::NonTerm(Op::Print, vec![val])
+            }
+            Token {
+                kind: TokenType::Return,
+                ..
+            } => {
+                self.advance();
+                let mut value = Vec::new();
+                if !self.expect_semicolon() {
+                    value.push(self.parse_expr(0)?);
+                }
+                if !self.expect_semicolon() {
+                    return self.error("Expected ;");
+                };
+                self.advance();
+                Tree::NonTerm(Op::Return, value)
+            }
+            Token {
+                kind: TokenType::Import,
+                ..
+            } => {
+                self.advance();
+                if !self.expect(TokenType::String) {
+                    return self.error("Expected the path of a file in quotes after import");
+                };
+                let path = self.advance();
+                let path = self.ast.push(Tree::Atom(Atom::String), path.range);
+                if !self.expect_semicolon() {
+                    return self.error("Expected ;");
+                };
+                self.advance();
+                Tree::NonTerm(Op::Import, vec![path])
+            }
+            n @ Token {
+                kind: TokenType::Break | TokenType::Continue,
+                ..
+            } => {
+                self.advance();
+                if !self.expect_semicolon() {
+                    return self.error("Expected ;");
+                };
+                self.advance();
+                Tree::NonTerm(self.to_op(&n)?, vec![])
             }
             Token {
                 kind: TokenType::Else,
@@ -427,7 +801,7 @@ impl Parser {
             } => {
                 self.advance();
                 let block = self.parse_statment()?;
-                return Ok(Tree::NonTerm(Op::Else, vec![block]));
+                Tree::NonTerm(Op::Else, vec![block])
             }
 
             Token {
@@ -438,20 +812,24 @@ impl Parser {
                 if !self.expect(TokenType::Identifier) {
                     return self.error("Expected name of class");
                 };
-                self.advance();
-                let cls_name = Tree::Atom(Atom::Ident {
-                    range: Range {
-                        start: self.current().range.start,
-                        end: self.current().range.end,
-                    },
-                    source: Arc::clone(&self.input),
-                });
+                let range = self.advance().range;
+                let cls_name = self.ast.push(Tree::Atom(Atom::Ident), range);
+                let mut children = vec![cls_name];
+                // `class B < A` inherits from `A`.
+                if self.expect(TokenType::Less) {
+                    self.advance();
+                    if !self.expect(TokenType::Identifier) {
+                        return self.error("Expected name of superclass");
+                    };
+                    let range = self.advance().range;
+                    children.push(self.ast.push(Tree::Atom(Atom::Ident), range));
+                }
                 if !self.expect(TokenType::LeftBrace) {
                     return self.error("Expected body");
                 };
 
-                let inside = self.parse_statment()?;
-                return Ok(Tree::NonTerm(Op::Class, vec![cls_name, inside]));
+                children.push(self.parse_statment()?);
+                Tree::NonTerm(Op::Class, children)
             }
 
             Token {
@@ -459,28 +837,20 @@ impl Parser {
                 ..
             } => {
                 self.advance();
-                if self.expect(TokenType::LeftParen) {
-                    self.advance();
-                    let var = self.parse_expr(0)?;
-                    if !self.expect(TokenType::Semicolon) {
-                        return self.error("Expected a ;");
-                    }
-                    self.advance();
-                    let cond = self.parse_expr(0)?;
-                    if !self.expect(TokenType::Semicolon) {
-                        return self.error("Expected a ;");
-                    }
-                    self.advance();
-                    let inc = self.parse_expr(0)?;
-                    if !self.expect(TokenType::RightParen) {
-                        return self.error("Expected a )");
-                    };
-                    self.advance();
-                    let block = self.parse_statment()?;
-                    return Ok(Tree::NonTerm(Op::For, vec![var, cond, inc, block]));
-                } else {
+                if !self.expect(TokenType::LeftParen) {
                     return self.error("Expected a (");
+                }
+                self.advance();
+                // A `var` declaration takes its own `;`.
+                let var = if self.expect(TokenType::Var) {
+                    self.parse_statment()?
+                } else {
+                    self.parse_for_clause(TokenType::Semicolon)?
                 };
+                let cond = self.parse_for_clause(TokenType::Semicolon)?;
+                let inc = self.parse_for_clause(TokenType::RightParen)?;
+                let block = self.parse_statment()?;
+                Tree::NonTerm(Op::For, vec![var, cond, inc, block])
             }
             Token {
                 kind: TokenType::While,
@@ -520,6 +890,11 @@ impl Parser {
                     return self.error("Expected a {");
                 };
                 let block = self.parse_statment()?;
+                if self.expect(TokenType::Else) {
+                    self.advance();
+                    let other = self.parse_statment()?;
+                    return Ok(self.push(start, Tree::NonTerm(Op::If, vec![cond, block, other])));
+                }
                 Tree::NonTerm(Op::If, vec![cond, block])
             }
             Token {
@@ -528,74 +903,76 @@ impl Parser {
             } => {
                 let mut child = Vec::new();
                 self.advance();
-                while !self.expect(TokenType::RightBrace) {
+                while !self.expect(TokenType::RightBrace) && !self.expect(TokenType::Eof) {
                     let val = self.parse_statment()?;
-                    match val {
-                        Tree::Atom(x) => match x {
-                            Atom::Nil => {
-                                break;
-                            }
-                            _ => continue,
-                        },
-                        x => {
-                            child.push(x);
-                        }
+                    if !matches!(self.ast[val], Tree::Atom(_)) {
+                        child.push(val);
                     }
                 }
                 if !self.expect(TokenType::RightBrace) {
                     return self.error("Expected } ");
                 };
                 self.advance();
-                return Ok(Tree::NonTerm(Op::Group, child));
-            }
-            _ => {
-                if self.expect(TokenType::Eof) {
-                    return Ok(Tree::Atom(Atom::Nil));
-                }
-                let val = self.parse_expr(0)?;
-                if self.expect(TokenType::Semicolon) {
-                    self.advance();
-                    let mut temp: Vec<Tree> = Vec::new();
-                    temp.push(val);
-                    return Ok(Tree::ExprStatment(temp));
-                }
-                return Ok(val);
+                Tree::NonTerm(Op::Group, child)
             }
+            _ => return self.parse_expr_statment(),
         };
-        Ok(lhs)
+        Ok(self.push(start, lhs))
+    }
+
+    fn parse_expr_statment(&mut self) -> Result<NodeId, miette::Report> {
+        let start = self.peek().range.start;
+        let val = self.parse_expr(0)?;
+        if self.expect(TokenType::Semicolon) {
+            self.advance();
+            return Ok(self.push(start, Tree::ExprStatment(vec![val])));
+        }
+        Ok(val)
     }
 }
 
-fn prefix_binding_power(op: scanner::TokenType) -> ((), u8) {
+// Loosest to tightest: assignment, `or`, `and`, equality, comparison, `|`,
+// `^`, `&`, shifts, `+ -`, `* / %`, prefix `- !`, `**`, postfix, `.`.
+// Bitwise operators bind tighter than comparisons, so `x & 1 == 0` tests the
+// low bit, and `**` tighter than a prefix minus on its left, `-2 ** 2` is -4.
+fn prefix_binding_power(op: scanner::TokenType) -> Option<((), u8)> {
     match op {
-        TokenType::Plus | TokenType::Minus | TokenType::Print => ((), 5),
-        _ => {
-            panic!("woops bad token this should be a error")
-        }
+        TokenType::Plus | TokenType::Minus | TokenType::Bang => Some(((), 23)),
+        // Binds the operand as tightly as a call or index does, `++xs[0]`.
+        TokenType::PlusPlus | TokenType::MinusMinus => Some(((), 27)),
+        _ => None,
     }
 }
 
 fn infix_binding_power(op: scanner::TokenType) -> Option<(u8, u8)> {
-    let res = match op.into() {
-        Op::Equal => (2, 1),
-        Op::And | Op::Or => (3, 4),
-        Op::BangEqual
-        | Op::EqualEqual
-        | Op::Less
-        | Op::LessEqual
-        | Op::Greater
-        | Op::GreaterEqual => (5, 6),
-        Op::Plus | Op::Minus => (7, 8),
-        Op::Star | Op::Slash => (9, 10),
-        Op::Field => (16, 15),
+    let res = match Op::try_from(op).ok()? {
+        Op::Equal
+        | Op::PlusEqual
+        | Op::MinusEqual
+        | Op::StarEqual
+        | Op::SlashEqual
+        | Op::PercentEqual => (2, 1),
+        Op::Or => (3, 4),
+        Op::And => (5, 6),
+        Op::BangEqual | Op::EqualEqual => (7, 8),
+        Op::Less | Op::LessEqual | Op::Greater | Op::GreaterEqual => (9, 10),
+        Op::Pipe => (11, 12),
+        Op::Caret => (13, 14),
+        Op::Ampersand => (15, 16),
+        Op::LessLess | Op::GreaterGreater => (17, 18),
+        Op::Plus | Op::Minus => (19, 20),
+        Op::Star | Op::Slash | Op::Percent => (21, 22),
+        // Right associative, `2 ** 3 ** 2` is `2 ** (3 ** 2)`.
+        Op::StarStar => (25, 24),
+        Op::Field => (30, 31),
         _ => return None,
     };
     Some(res)
 }
 
 fn postfix_binding_power(op: scanner::TokenType) -> Option<(u8, ())> {
-    let res = match op.into() {
-        Op::Call => (13, ()),
+    let res = match Op::try_from(op).ok()? {
+        Op::Call | Op::Index | Op::PlusPlus | Op::MinusMinus => (27, ()),
         _ => return None,
     };
     Some(res)
@@ -603,106 +980,196 @@ fn postfix_binding_power(op: scanner::TokenType) -> Option<(u8, ())> {
 
 impl std::fmt::Display for Op {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Op::Minus => "-",
-                Op::Equal => "=",
-                Op::Plus => "+",
-                Op::Star => "*",
-                Op::BangEqual => "!=",
-                Op::EqualEqual => "==",
-                Op::LessEqual => "<=",
-                Op::GreaterEqual => ">=",
-                Op::Less => "<",
-                Op::Greater => ">",
-                Op::If => "if",
-                Op::Else => "else",
-                Op::Slash => "/",
-                Op::Bang => "!",
-                Op::And => "and",
-                Op::Or => "or",
-                Op::For => "for",
-                Op::Class => "class",
-                Op::Print => "print",
-                Op::Return => "return",
-                Op::Field => ".",
-                Op::Var => "var",
-                Op::While => "while",
-                Op::Call => "call",
-                Op::Group => "group",
-            }
-        )
+        write!(f, "{}", self.name())
     }
 }
 
-impl std::fmt::Display for Atom {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl Op {
+    /// Every operator, so its name can be mapped back with [`Op::name`].
+    pub const ALL: [Op; 46] = [
+        Op::Minus,
+        Op::If,
+        Op::Else,
+        Op::Plus,
+        Op::Star,
+        Op::BangEqual,
+        Op::EqualEqual,
+        Op::LessEqual,
+        Op::GreaterEqual,
+        Op::Less,
+        Op::Greater,
+        Op::Slash,
+        Op::Bang,
+        Op::And,
+        Op::Or,
+        Op::Call,
+        Op::For,
+        Op::Class,
+        Op::Print,
+        Op::Return,
+        Op::Field,
+        Op::Var,
+        Op::While,
+        Op::Group,
+        Op::Equal,
+        Op::List,
+        Op::Index,
+        Op::Break,
+        Op::Continue,
+        Op::Percent,
+        Op::PlusEqual,
+        Op::MinusEqual,
+        Op::StarEqual,
+        Op::SlashEqual,
+        Op::PercentEqual,
+        Op::PlusPlus,
+        Op::MinusMinus,
+        Op::PostPlusPlus,
+        Op::PostMinusMinus,
+        Op::Import,
+        Op::StarStar,
+        Op::Ampersand,
+        Op::Pipe,
+        Op::Caret,
+        Op::LessLess,
+        Op::GreaterGreater,
+    ];
+
+    /// `+=` and the like, which combine the target's value with the right hand side.
+    pub fn is_compound_assignment(&self) -> bool {
+        matches!(
+            self,
+            Op::PlusEqual | Op::MinusEqual | Op::StarEqual | Op::SlashEqual | Op::PercentEqual
+        )
+    }
+
+    pub fn name(&self) -> &'static str {
         match self {
-            Atom::Ident { range, source } => write!(f, "{}", &source[range.start..range.end]),
-            Atom::String { range, source } => {
-                write!(f, "{}", &source[range.start..range.end])
-            }
-            Atom::Number(x) => write!(f, "{x}"),
-            Atom::Nil => write!(f, "Nil"),
-            Atom::Bool(x) => write!(f, "{x}"),
-            Atom::This => write!(f, "This"),
-            Atom::Super => write!(f, "Super"),
-            Atom::Error => write!(f, "Error"),
+            Op::Minus => "-",
+            Op::Equal => "=",
+            Op::Plus => "+",
+            Op::Star => "*",
+            Op::BangEqual => "!=",
+            Op::EqualEqual => "==",
+            Op::LessEqual => "<=",
+            Op::GreaterEqual => ">=",
+            Op::Less => "<",
+            Op::Greater => ">",
+            Op::If => "if",
+            Op::Else => "else",
+            Op::Slash => "/",
+            Op::Bang => "!",
+            Op::And => "and",
+            Op::Or => "or",
+            Op::For => "for",
+            Op::Class => "class",
+            Op::Print => "print",
+            Op::Return => "return",
+            Op::Field => ".",
+            Op::Var => "var",
+            Op::While => "while",
+            Op::Call => "call",
+            Op::Group => "group",
+            Op::List => "list",
+            Op::Index => "index",
+            Op::Break => "break",
+            Op::Continue => "continue",
+            Op::Percent => "%",
+            Op::PlusEqual => "+=",
+            Op::MinusEqual => "-=",
+            Op::StarEqual => "*=",
+            Op::SlashEqual => "/=",
+            Op::PercentEqual => "%=",
+            Op::PlusPlus => "++",
+            Op::MinusMinus => "--",
+            Op::PostPlusPlus => "post++",
+            Op::PostMinusMinus => "post--",
+            Op::Import => "import",
+            Op::StarStar => "**",
+            Op::Ampersand => "&",
+            Op::Pipe => "|",
+            Op::Caret => "^",
+            Op::LessLess => "<<",
+            Op::GreaterGreater => ">>",
         }
     }
 }
 
-impl std::fmt::Display for Tree {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Tree::ExprStatment(op) => {
-                write!(f, "(")?;
+/// An S-expression view of a subtree, see [`Ast::sexpr`]; [`crate::sexpr::read`] parses the output back.
+///
+/// Operators use their symbol as the head, `(+ a 1)`, the other nodes use
+/// `(expr ..)`, `(define name value?)`, `(apply callee args..)`,
+/// `(fun name (params..) body)` and `(lambda (params..) body)`. Nodes that have
+/// no source spelling are written with a `#`, `#nil`, `#error` and `#+` for `Tree::Op`,
+/// as are numbers too large for an `f64`, `#inf`, and the `#-inf` and `#nan` no
+/// literal gives.
+pub struct Sexpr<'a> {
+    ast: &'a Ast,
+    id: NodeId,
+}
 
-                for i in op {
-                    write!(f, ", {}", i)?
-                }
-                write!(f, ")")
-            }
-            Tree::Op(op) => {
-                write!(f, "{}", op)
-            }
-            Tree::Nil => {
-                write!(f, "EOF")
+impl std::fmt::Display for Sexpr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ast = self.ast;
+        let list = |f: &mut std::fmt::Formatter<'_>, head: &str, ids: &[NodeId]| {
+            write!(f, "({head}")?;
+            for i in ids {
+                write!(f, " {}", ast.sexpr(*i))?;
             }
-            Tree::Atom(x) => {
-                write!(f, "{}", x)
+            write!(f, ")")
+        };
+        let parameters = |f: &mut std::fmt::Formatter<'_>, parameters: &[NodeId]| {
+            write!(f, "(")?;
+            for (i, param) in parameters.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", ast.sexpr(*param))?;
             }
-            Tree::NonTerm(parent, children) => {
-                write!(f, "({}", parent)?;
-
-                for i in children {
-                    write!(f, ", {}", i)?
+            write!(f, ")")
+        };
+        match &ast[self.id] {
+            Tree::Nil => write!(f, "#nil"),
+            Tree::Op(op) => write!(f, "#{op}"),
+            Tree::Atom(Atom::Ident | Atom::String) => write!(f, "{}", ast.text(self.id)),
+            Tree::Atom(Atom::Number(x)) if x.is_nan() => write!(f, "#nan"),
+            Tree::Atom(Atom::Number(x)) if x.is_infinite() => write!(f, "#{x}"),
+            Tree::Atom(Atom::Number(x)) => write!(f, "{x}"),
+            Tree::Atom(Atom::Bool(x)) => write!(f, "{x}"),
+            Tree::Atom(Atom::Nil) => write!(f, "nil"),
+            Tree::Atom(Atom::This) => write!(f, "this"),
+            Tree::Atom(Atom::Super) => write!(f, "super"),
+            Tree::Atom(Atom::Error) => write!(f, "#error"),
+            Tree::ExprStatment(ids) => list(f, "expr", ids),
+            Tree::NonTerm(op, ids) => list(f, op.name(), ids),
+            Tree::Var(name, val) => match val {
+                Some(val) => write!(f, "(define {} {})", ast.sexpr(*name), ast.sexpr(*val)),
+                None => write!(f, "(define {})", ast.sexpr(*name)),
+            },
+            Tree::Call { callee, arguments } => {
+                write!(f, "(apply {}", ast.sexpr(*callee))?;
+                for i in arguments {
+                    write!(f, " {}", ast.sexpr(*i))?;
                 }
                 write!(f, ")")
             }
             Tree::Fun {
                 name,
-                parameters,
+                parameters: params,
                 body,
             } => {
-                write!(f, "{}", name)?;
-                write!(f, "(")?;
-                for i in parameters {
-                    write!(f, " {} ", i)?
-                }
-                write!(f, ")")?;
-                write!(f, " {{ {} }}", body)
+                write!(f, "(fun {} ", ast.sexpr(*name))?;
+                parameters(f, params)?;
+                write!(f, " {})", ast.sexpr(*body))
             }
-
-            Tree::Var(k, x) => {
-                write!(f, "(")?;
-                write!(f, "k: {}", k)?;
-                write!(f, "{:?}", x)?;
-                write!(f, ")")
+            Tree::Lambda {
+                parameters: params,
+                body,
+            } => {
+                write!(f, "(lambda ")?;
+                parameters(f, params)?;
+                write!(f, " {})", ast.sexpr(*body))
             }
-            _ => unimplemented!(),
         }
     }
 }
@@ -1,13 +1,15 @@
+use crate::source::Source;
 use miette::{Diagnostic, Error, Result, SourceSpan};
 use std::collections::HashMap;
 use std::ops::Range;
 use std::str::Chars;
+use std::sync::Arc;
 
 #[derive(Debug, Diagnostic)]
 #[diagnostic(help("This is most likely a invalid token (some char not allowed in the language)"))]
 pub struct MyBad {
     #[source_code]
-    source: String,
+    source: Arc<Source>,
     #[label("main issue")]
     primary_span: SourceSpan,
 }
@@ -19,6 +21,22 @@ impl std::fmt::Display for MyBad {
     }
 }
 
+#[derive(Debug, Diagnostic)]
+#[diagnostic(help("a string has to end with a \" before the end of the file"))]
+pub struct UnterminatedString {
+    #[source_code]
+    source: Arc<Source>,
+    #[label("the string starts here")]
+    primary_span: SourceSpan,
+}
+
+impl std::error::Error for UnterminatedString {}
+impl std::fmt::Display for UnterminatedString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unterminated string")
+    }
+}
+
 impl std::fmt::Display for TokenType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -49,6 +67,20 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    SlashEqual,
+    Percent,
+    PercentEqual,
+    PlusPlus,
+    MinusMinus,
+    StarStar,
+    Ampersand,
+    Pipe,
+    Caret,
+    LessLess,
+    GreaterGreater,
 
     // Literals.
     Identifier,
@@ -57,12 +89,15 @@ pub enum TokenType {
 
     // Keywords.
     And,
+    Break,
     Class,
+    Continue,
     Else,
     False,
     For,
     Fun,
     If,
+    Import,
     Nil,
     Or,
     Print,
@@ -91,6 +126,7 @@ pub struct Token {
 
 pub struct Scanner<'a> {
     keywords: HashMap<&'a str, TokenType>,
+    source: &'a Arc<Source>,
     code: &'a str,
     current: usize,
     start: usize,
@@ -117,7 +153,7 @@ impl<'a> Iterator for Scanner<'a> {
             };
 
         self.handle_whitespace();
-        self.current = self.code[self.chars.as_str().len()..].len();
+        self.current = self.offset();
         self.start = self.current;
         let c = self.chars.next()?;
         let third_state = match c {
@@ -157,48 +193,54 @@ impl<'a> Iterator for Scanner<'a> {
                 self.current += c.len_utf8();
                 return generate(TokenType::Dot, self.current, self.start);
             }
-            '-' => {
-                self.current += c.len_utf8();
-                return generate(TokenType::Minus, self.current, self.start);
-            }
-            '+' => {
+            '&' => {
                 self.current += c.len_utf8();
-                return generate(TokenType::Plus, self.current, self.start);
+                return generate(TokenType::Ampersand, self.current, self.start);
             }
-            '/' => {
+            '|' => {
                 self.current += c.len_utf8();
-                return generate(TokenType::Slash, self.current, self.start);
+                return generate(TokenType::Pipe, self.current, self.start);
             }
-            '*' => {
+            '^' => {
                 self.current += c.len_utf8();
-                return generate(TokenType::Star, self.current, self.start);
+                return generate(TokenType::Caret, self.current, self.start);
             }
-            '\0' => {
-                // println!("eof");
-                return generate(TokenType::Eof, self.current, self.start);
-            }
-            '!' | '=' | '<' | '>' => ThirdState::OrEquals(c),
-            'A'..='Z' | 'a'..='z' => ThirdState::Iden,
+            '!' | '=' | '<' | '>' | '-' | '+' | '/' | '*' | '%' => ThirdState::OrEquals(c),
+            'A'..='Z' | 'a'..='z' | '_' => ThirdState::Iden,
             '0'..='9' => ThirdState::Number,
             '"' => ThirdState::String,
             _ => {
                 return Some(Err(MyBad {
-                    source: self.code.into(),
+                    source: Arc::clone(self.source),
                     primary_span: SourceSpan::new(self.start.into(), self.lexeme().len().into()),
                 }
                 .into()));
             }
         };
 
+        if let ThirdState::OrEquals(c @ ('+' | '-' | '*' | '<' | '>')) = third_state
+            && self.first() == c
+        {
+            self.chars.next()?;
+            self.current = self.offset();
+            let kind = match c {
+                '+' => TokenType::PlusPlus,
+                '-' => TokenType::MinusMinus,
+                '*' => TokenType::StarStar,
+                '<' => TokenType::LessLess,
+                _ => TokenType::GreaterGreater,
+            };
+            return generate(kind, self.current, self.start);
+        }
         let mut comparator_handle =
             |kind: TokenType, kind2: TokenType| -> Option<Result<Token, Error>> {
                 {
                     if self.first() == '=' {
                         self.chars.next()?;
-                        self.current = self.code[self.chars.as_str().len()..].len();
+                        self.current = self.offset();
                         return generate(kind, self.current, self.start);
                     } else {
-                        self.current = self.code[self.chars.as_str().len()..].len();
+                        self.current = self.offset();
                         return generate(kind2, self.current, self.start);
                     }
                 }
@@ -217,9 +259,24 @@ impl<'a> Iterator for Scanner<'a> {
                 '=' => {
                     return comparator_handle(TokenType::EqualEqual, TokenType::Equal);
                 }
+                '+' => {
+                    return comparator_handle(TokenType::PlusEqual, TokenType::Plus);
+                }
+                '-' => {
+                    return comparator_handle(TokenType::MinusEqual, TokenType::Minus);
+                }
+                '*' => {
+                    return comparator_handle(TokenType::StarEqual, TokenType::Star);
+                }
+                '/' => {
+                    return comparator_handle(TokenType::SlashEqual, TokenType::Slash);
+                }
+                '%' => {
+                    return comparator_handle(TokenType::PercentEqual, TokenType::Percent);
+                }
                 _ => {
                     return Some(Err(MyBad {
-                        source: self.code.into(),
+                        source: Arc::clone(self.source),
                         primary_span: SourceSpan::new(
                             self.start.into(),
                             self.lexeme().len().into(),
@@ -230,14 +287,15 @@ impl<'a> Iterator for Scanner<'a> {
             },
 
             ThirdState::String => {
-                if let Some(c) = self.chars.find(|&x| x == '"') {
-                    if c == '"' {
-                        self.current = self.code[self.chars.as_str().len()..].len();
-                        return generate(TokenType::String, self.current, self.start);
-                    } else {
-                        return generate(TokenType::String, self.current, self.start);
-                    }
+                if self.chars.any(|x| x == '"') {
+                    self.current = self.offset();
+                    return generate(TokenType::String, self.current, self.start);
                 }
+                return Some(Err(UnterminatedString {
+                    source: Arc::clone(self.source),
+                    primary_span: SourceSpan::new(self.start.into(), 1),
+                }
+                .into()));
             }
 
             ThirdState::Number => {
@@ -252,7 +310,7 @@ impl<'a> Iterator for Scanner<'a> {
                     }
                 }
 
-                self.current = self.code[self.chars.as_str().len()..].len();
+                self.current = self.offset();
                 match self.lexeme().parse::<f64>() {
                     Ok(x) => {
                         return generate(TokenType::Number(x), self.current, self.start);
@@ -262,10 +320,10 @@ impl<'a> Iterator for Scanner<'a> {
             }
 
             ThirdState::Iden => {
-                while self.first().is_ascii_alphanumeric() {
+                while self.first().is_ascii_alphanumeric() || self.first() == '_' {
                     self.chars.next();
                 }
-                self.current = self.code[self.chars.as_str().len()..].len();
+                self.current = self.offset();
                 let index = self.lexeme();
                 // println!("lexem: {index}");
                 if let Some(c) = self.keywords.get_mut(&(index)) {
@@ -280,15 +338,19 @@ impl<'a> Iterator for Scanner<'a> {
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(code: &'a str) -> Scanner<'a> {
+    pub fn new(source: &'a Arc<Source>) -> Scanner<'a> {
+        let code = source.text.as_str();
         let mut keywords = HashMap::with_capacity(16);
         keywords.insert("else", TokenType::Else);
         keywords.insert("and", TokenType::And);
+        keywords.insert("break", TokenType::Break);
+        keywords.insert("continue", TokenType::Continue);
         keywords.insert("for", TokenType::For);
         keywords.insert("fun", TokenType::Fun);
         keywords.insert("class", TokenType::Class);
         keywords.insert("return", TokenType::Return);
         keywords.insert("if", TokenType::If);
+        keywords.insert("import", TokenType::Import);
         keywords.insert("nil", TokenType::Nil);
         keywords.insert("or", TokenType::Or);
         keywords.insert("true", TokenType::True);
@@ -301,6 +363,7 @@ impl<'a> Scanner<'a> {
 
         Scanner {
             keywords,
+            source,
             code,
             current: 0,
             start: 0,
@@ -308,15 +371,38 @@ impl<'a> Scanner<'a> {
             line: 1,
         }
     }
+
+    /// Scans from byte `offset`, which must be where a token ends or starts.
+    pub fn starting_at(source: &'a Arc<Source>, offset: usize) -> Scanner<'a> {
+        let mut scanner = Scanner::new(source);
+        scanner.chars = scanner.code[offset..].chars();
+        scanner
+    }
     fn handle_whitespace(&mut self) {
-        while matches!(self.first(), '\n' | ' ') {
-            if self.first() == '\n' {
-                self.line += 1;
-            };
-            self.chars.next().unwrap_or('\0');
-            self.current = self.code[self.chars.as_str().len()..].len();
+        loop {
+            match self.first() {
+                '\n' => {
+                    self.line += 1;
+                    self.chars.next();
+                }
+                ' ' | '\t' | '\r' => {
+                    self.chars.next();
+                }
+                // `//` comments run to the end of the line
+                '/' if self.second() == '/' => {
+                    while self.chars.clone().next().is_some_and(|x| x != '\n') {
+                        self.chars.next();
+                    }
+                }
+                _ => break,
+            }
         }
-        self.current = self.code[self.chars.as_str().len()..].len();
+        self.current = self.offset();
+    }
+
+    // Byte offset of the next unread char.
+    fn offset(&self) -> usize {
+        self.code.len() - self.chars.as_str().len()
     }
 
     fn lexeme(&mut self) -> &'a str {
@@ -334,27 +420,27 @@ impl<'a> Scanner<'a> {
     }
 }
 
-pub fn collect(input: &str) -> Result<Vec<Token>, miette::Report> {
-    let mut iter = Scanner::new(input).into_iter();
+pub fn collect(source: &Arc<Source>) -> Result<Vec<Token>, miette::Report> {
+    let input = source.text.as_str();
+    let mut iter = Scanner::new(source).into_iter();
     let mut tokens = Vec::new();
     while let Some(res) = iter.next() {
         let token = res?;
         tokens.push(token);
     }
-    if input.len() == 0 {
-        tokens.push(Token {
-            kind: TokenType::Eof,
-            range: Range { start: 0, end: 0 },
-        });
-        return Ok(tokens);
-    }
-    tokens.push(Token {
+    tokens.push(eof(input));
+    Ok(tokens)
+}
+
+/// The token that ends every stream, it points at the last character, which may
+/// be wider than one byte.
+pub fn eof(input: &str) -> Token {
+    let last = input.char_indices().last().map_or(0, |(i, _)| i);
+    Token {
         kind: TokenType::Eof,
         range: Range {
-            start: input.len() - '\0'.len_utf8(),
+            start: last,
             end: input.len(),
         },
-    });
-
-    Ok(tokens)
+    }
 }
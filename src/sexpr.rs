@@ -0,0 +1,251 @@
+use crate::parser::{Ast, Atom, NodeId, Op, Tree};
+use crate::source::Source;
+use miette::{Context, Diagnostic, Result, SourceSpan};
+use std::{ops::Range, sync::Arc};
+
+#[derive(Debug, Diagnostic)]
+#[diagnostic(help("the S-expression does not describe a syntax tree"))]
+pub struct SexprError {
+    #[source_code]
+    source: Arc<Source>,
+    #[label("main issue")]
+    primary_span: SourceSpan,
+}
+
+impl std::error::Error for SexprError {}
+impl std::fmt::Display for SexprError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid S-expression")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Open,
+    Close,
+    String,
+    Word,
+}
+
+/// Reads the S-expressions written by [`Ast::sexpr`] back into a program, one
+/// top level statement per form.
+///
+/// The source of the result is `input`, so node spans point at the forms they
+/// were read from and printing them again gives the same text.
+pub fn read(input: impl Into<Source>) -> Result<Ast, miette::Report> {
+    let source = Arc::new(input.into());
+    let mut reader = Reader {
+        tokens: tokenize(&source.text),
+        ast: Ast::new(Arc::clone(&source)),
+        source,
+        pos: 0,
+    };
+    while reader.pos < reader.tokens.len() {
+        let id = reader.form()?;
+        reader.ast.program.push(id);
+    }
+    Ok(reader.ast)
+}
+
+fn tokenize(input: &str) -> Vec<(Kind, Range<usize>)> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            '(' => Kind::Open,
+            ')' => Kind::Close,
+            '"' => {
+                // Lox strings have no escapes, they run to the next quote.
+                let end = match chars.find(|(_, x)| *x == '"') {
+                    Some((i, _)) => i + 1,
+                    None => input.len(),
+                };
+                tokens.push((Kind::String, start..end));
+                continue;
+            }
+            x if x.is_whitespace() => continue,
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, x)) = chars.peek() {
+                    if x.is_whitespace() || matches!(x, '(' | ')' | '"') {
+                        break;
+                    }
+                    end = i + x.len_utf8();
+                    chars.next();
+                }
+                tokens.push((Kind::Word, start..end));
+                continue;
+            }
+        };
+        tokens.push((kind, start..start + c.len_utf8()));
+    }
+    tokens
+}
+
+struct Reader {
+    tokens: Vec<(Kind, Range<usize>)>,
+    source: Arc<Source>,
+    ast: Ast,
+    pos: usize,
+}
+
+impl Reader {
+    fn next(&mut self) -> Result<(Kind, Range<usize>), miette::Report> {
+        match self.tokens.get(self.pos) {
+            Some(x) => {
+                self.pos += 1;
+                Ok(x.clone())
+            }
+            None => {
+                let end = self.source.text.len();
+                self.error("unexpected end of input, a ) is missing", end..end)
+            }
+        }
+    }
+
+    fn peek_is(&self, kind: Kind) -> bool {
+        self.tokens.get(self.pos).is_some_and(|(x, _)| *x == kind)
+    }
+
+    fn form(&mut self) -> Result<NodeId, miette::Report> {
+        let (kind, range) = self.next()?;
+        let tree = match kind {
+            Kind::Open => return self.list(range.start),
+            Kind::Close => return self.error("unexpected )", range),
+            Kind::String if range.len() < 2 || !self.source.text[range.clone()].ends_with('"') => {
+                return self.error("unterminated string, a \" is missing", range);
+            }
+            Kind::String => Tree::Atom(Atom::String),
+            Kind::Word => self.word(range.clone())?,
+        };
+        Ok(self.ast.push(tree, range))
+    }
+
+    fn word(&self, range: Range<usize>) -> Result<Tree, miette::Report> {
+        let word = &self.source.text[range.clone()];
+        let atom = match word {
+            "#nil" => return Ok(Tree::Nil),
+            "#error" => Atom::Error,
+            "nil" => Atom::Nil,
+            "true" => Atom::Bool(true),
+            "false" => Atom::Bool(false),
+            "this" => Atom::This,
+            "super" => Atom::Super,
+            "#inf" => Atom::Number(f64::INFINITY),
+            "#-inf" => Atom::Number(f64::NEG_INFINITY),
+            "#nan" => Atom::Number(f64::NAN),
+            x if x.starts_with('#') => match op_named(&x[1..]) {
+                Some(op) => return Ok(Tree::Op(op)),
+                None => return self.error("unknown operator", range),
+            },
+            x if x
+                .trim_start_matches('-')
+                .starts_with(|c: char| c.is_ascii_digit()) =>
+            {
+                match x.parse::<f64>() {
+                    Ok(n) => Atom::Number(n),
+                    Err(_) => return self.error("invalid number", range),
+                }
+            }
+            _ => Atom::Ident,
+        };
+        Ok(Tree::Atom(atom))
+    }
+
+    // Everything after an opening paren, up to and including its closing paren.
+    fn list(&mut self, start: usize) -> Result<NodeId, miette::Report> {
+        let (kind, range) = self.next()?;
+        if kind != Kind::Word {
+            return self.error("a list must start with the name of a node", range);
+        }
+        let output = match &self.source.text[range.clone()] {
+            "expr" => Tree::ExprStatment(self.rest()?),
+            "define" => {
+                let name = self.form()?;
+                if self.ast[name] != Tree::Atom(Atom::Ident) {
+                    return self.error("expected a variable name", self.ast.span(name));
+                }
+                let val = match self.peek_is(Kind::Close) {
+                    true => None,
+                    false => Some(self.form()?),
+                };
+                self.close()?;
+                Tree::Var(name, val)
+            }
+            "apply" => {
+                let callee = self.form()?;
+                Tree::Call {
+                    callee,
+                    arguments: self.rest()?,
+                }
+            }
+            "fun" => {
+                let name = self.form()?;
+                let parameters = self.parameters()?;
+                let body = self.form()?;
+                self.close()?;
+                Tree::Fun {
+                    name,
+                    parameters,
+                    body,
+                }
+            }
+            "lambda" => {
+                let parameters = self.parameters()?;
+                let body = self.form()?;
+                self.close()?;
+                Tree::Lambda { parameters, body }
+            }
+            head => match op_named(head) {
+                Some(op) => Tree::NonTerm(op, self.rest()?),
+                None => return self.error("unknown node", range),
+            },
+        };
+        let end = self.tokens[self.pos - 1].1.end;
+        Ok(self.ast.push(output, start..end))
+    }
+
+    // The remaining forms of a list, consuming its closing paren.
+    fn rest(&mut self) -> Result<Vec<NodeId>, miette::Report> {
+        let mut output = Vec::new();
+        while !self.peek_is(Kind::Close) {
+            output.push(self.form()?);
+        }
+        self.close()?;
+        Ok(output)
+    }
+
+    fn parameters(&mut self) -> Result<Vec<NodeId>, miette::Report> {
+        let (kind, range) = self.next()?;
+        if kind != Kind::Open {
+            return self.error("expected a parameter list", range);
+        }
+        let output = self.rest()?;
+        for i in &output {
+            if self.ast[*i] != Tree::Atom(Atom::Ident) {
+                return self.error("parameters must be names", self.ast.span(*i));
+            }
+        }
+        Ok(output)
+    }
+
+    fn close(&mut self) -> Result<(), miette::Report> {
+        let (kind, range) = self.next()?;
+        if kind != Kind::Close {
+            return self.error("expected )", range);
+        }
+        Ok(())
+    }
+
+    fn error<T>(&self, message: &str, range: Range<usize>) -> Result<T, miette::Report> {
+        Err(SexprError {
+            source: Arc::clone(&self.source),
+            primary_span: SourceSpan::new(range.start.into(), range.end - range.start),
+        })
+        .wrap_err(String::from(message))
+    }
+}
+
+fn op_named(name: &str) -> Option<Op> {
+    Op::ALL.into_iter().find(|op| op.name() == name)
+}
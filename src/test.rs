@@ -60,12 +60,40 @@ fn complex_muilty_line() -> Result<(), Box<dyn std::error::Error>> {
 
 #[test]
 fn test_func() -> Result<(), Box<dyn std::error::Error>> {
-    let mut parse = parser::Parser::new(String::from(" fun hi(a + 1, b) { var a = 1; } "))?;
+    let mut parse = parser::Parser::new(String::from(" fun hi(a, b) { var a = 1; } "))?;
     let output = parse.parse_program()?;
     insta::assert_debug_snapshot!(output);
     Ok(())
 }
 #[test]
+fn test_func_bad_parameters() -> Result<(), Box<dyn std::error::Error>> {
+    for code in [
+        "fun hi(a + 1, b) {}",
+        "fun hi(a, a) {}",
+        "fun hi(a,) {}",
+        "fun hi(a b) {}",
+        "fun hi(,) {}",
+    ] {
+        let mut parse = parser::Parser::new(String::from(code))?;
+        assert!(parse.parse_program().is_err(), "{code} should not parse");
+    }
+    Ok(())
+}
+#[test]
+fn test_func_parameter_limit() -> Result<(), Box<dyn std::error::Error>> {
+    let params = |n: usize| {
+        (0..n)
+            .map(|i| format!("p{i}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut parse = parser::Parser::new(format!("fun hi({}) {{}}", params(255)))?;
+    assert!(parse.parse_program().is_ok());
+    let mut parse = parser::Parser::new(format!("fun hi({}) {{}}", params(256)))?;
+    assert!(parse.parse_program().is_err());
+    Ok(())
+}
+#[test]
 fn test_class_simple() -> Result<(), Box<dyn std::error::Error>> {
     let mut parse = parser::Parser::new(String::from("class Hello {var i =1;} "))?;
     let output = parse.parse_program()?;
@@ -93,3 +121,1238 @@ fn test_print_statement() -> Result<(), Box<dyn std::error::Error>> {
     insta::assert_debug_snapshot!(output);
     Ok(())
 }
+#[test]
+fn test_lambda() -> Result<(), Box<dyn std::error::Error>> {
+    let mut parse = parser::Parser::new(String::from("var f = fun (a, b) { return a; };"))?;
+    let output = parse.parse_program()?;
+    insta::assert_debug_snapshot!(output);
+    Ok(())
+}
+#[test]
+fn test_lambda_closure() -> Result<(), Box<dyn std::error::Error>> {
+    let code = "
+fun counter() {
+  var n = 0;
+  return fun () { n = n + 1; return n; };
+}
+fun apply(f, x) { return f(x); }
+var c = counter();
+c();
+var count = c();
+var doubled = apply(fun (x) { return x * 2; }, 21);
+";
+    let mut scope = analysis::Resolver::new(parser::Parser::new(String::from(code))?);
+    let (program, resolution) = scope.resolve()?;
+    let mut runtime = interpreter::Interpreter::new();
+    runtime.interpret(&program, &resolution)?;
+    let env = runtime.env.borrow();
+    assert_eq!(env.get("count"), Some(interpreter::Value::Number(2.0)));
+    assert_eq!(env.get("doubled"), Some(interpreter::Value::Number(42.0)));
+    // A statement starting with `fun (` is a lambda expression, not a declaration.
+    assert_eq!(run("fun (x) { print x; }(1);")?, "1\n");
+    Ok(())
+}
+#[test]
+fn test_arity() -> Result<(), Box<dyn std::error::Error>> {
+    let err = run("fun f(a) { return a; } print f(1, 2);").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`f` takes 1 arguments but is called with 2: `f`"
+    );
+    // A function some assignment replaces is only checked when it is called.
+    let code = "fun f(a) { return a; } f = fun (a, b) { return a + b; }; print f(1, 2);";
+    assert_eq!(run(code)?, "3\n");
+    // Each call is checked against the declaration its name resolves to.
+    let code = "fun g(a, b) { return a + b; }
+fun outer() { fun g(a) { return a; } return g(1); }
+print g(1, 2); print outer();";
+    assert_eq!(run(code)?, "3\n1\n");
+    let err = run("fun g(a, b) {} { fun g(a) {} g(1, 2); }").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`g` takes 1 arguments but is called with 2: `g`"
+    );
+    // A global declared again may hold either value, before and after that.
+    let code = "fun f(a) {} var f = fun (a, b) { return a + b; }; print f(1, 2);";
+    assert_eq!(run(code)?, "3\n");
+    let code = "fun f(a) {} fun h() { return f(1, 2); }
+var f = fun (a, b) { return a + b; };
+print h();";
+    assert_eq!(run(code)?, "3\n");
+    Ok(())
+}
+#[test]
+fn test_stack_overflow() -> Result<(), Box<dyn std::error::Error>> {
+    // Calls nest deeper than the stack of a test thread holds in a debug
+    // build, so this runs with the 8 MiB the main thread gets.
+    let thread = std::thread::Builder::new().stack_size(8 << 20);
+    let outputs = thread.spawn(|| {
+        let code = format!(
+            "fun f(n) {{ if (n > 1) {{ return f(n - 1) + 1; }} return 1; }} print f({});",
+            interpreter::MAX_CALL_DEPTH
+        );
+        (run("fun f() { f(); } f();"), run(&code))
+    })?;
+    let (overflow, deepest) = outputs.join().unwrap();
+    assert_eq!(overflow.unwrap_err().to_string(), "Stack overflow.");
+    assert_eq!(deepest?, format!("{}\n", interpreter::MAX_CALL_DEPTH));
+    Ok(())
+}
+#[test]
+fn test_list_index() -> Result<(), Box<dyn std::error::Error>> {
+    let mut parse = parser::Parser::new(String::from("var xs = [1, 2, 3]; xs[0] = xs[2];"))?;
+    let output = parse.parse_program()?;
+    insta::assert_debug_snapshot!(output);
+    Ok(())
+}
+#[test]
+fn test_list_runtime() -> Result<(), Box<dyn std::error::Error>> {
+    let code = "var xs = [1, 2, [3]]; xs[0] = xs[2][0] + xs[1]; var first = xs[0];
+print first; print xs;";
+    assert_eq!(run(code)?, "5\n[5, 2, [3]]\n");
+    assert!(run("var xs = [1]; var y = xs[1];").is_err());
+    assert!(run("var xs = [1]; var y = xs[0.5];").is_err());
+    assert!(run("var xs = 1; var y = xs[0];").is_err());
+    Ok(())
+}
+#[test]
+fn test_var_uninitialized() -> Result<(), Box<dyn std::error::Error>> {
+    let mut parse = parser::Parser::new(String::from("var a; var b = a;"))?;
+    let output = parse.parse_program()?;
+    insta::assert_debug_snapshot!(output);
+    Ok(())
+}
+#[test]
+fn test_warn_unassigned() -> Result<(), Box<dyn std::error::Error>> {
+    let warnings = |code: &str| -> Result<usize, miette::Report> {
+        let mut scope = analysis::Resolver::new(parser::Parser::new(String::from(code))?);
+        scope.warn_unassigned = true;
+        scope.resolve()?;
+        Ok(scope.warnings.len())
+    };
+    assert_eq!(warnings("var a; var b = a; var c = a;")?, 2);
+    assert_eq!(warnings("var a; a = 1; var b = a;")?, 0);
+    assert_eq!(warnings("var a; { var b = a; } a = 2;")?, 0);
+    assert_eq!(warnings("{ var a; var b = a; }")?, 1);
+    let mut scope = analysis::Resolver::new(parser::Parser::new("var a; print a;")?);
+    scope.warn_unassigned = true;
+    scope.resolve()?;
+    assert_eq!(
+        scope.warnings[0].to_string(),
+        "`a` is read here but is never assigned, it is always nil"
+    );
+    // The message is the warning itself, not a wrapper around an empty one.
+    assert!(scope.warnings[0].chain().nth(1).is_none());
+    Ok(())
+}
+#[test]
+fn test_warn_unused() -> Result<(), Box<dyn std::error::Error>> {
+    let warnings = |code: &str| -> Result<Vec<String>, miette::Report> {
+        let mut scope = analysis::Resolver::new(parser::Parser::new(String::from(code))?);
+        scope.warn_unused = true;
+        scope.resolve()?;
+        let warnings = scope.warnings.iter().map(|x| x.to_string()).collect();
+        Ok(warnings)
+    };
+    let code = "fun f(a, b) { var c = 1; var d = 2; d = a; } fun g() {} g();";
+    assert_eq!(
+        warnings(code)?,
+        [
+            "parameter `b` is never used",
+            "`c` is never read",
+            "`d` is never read",
+            "function `f` is never called",
+        ]
+    );
+    let mut scope = analysis::Resolver::new(parser::Parser::new("{ var a = 1; }")?);
+    scope.warn_unused = true;
+    scope.resolve()?;
+    assert!(scope.warnings[0].chain().nth(1).is_none());
+    assert!(warnings("fun _f(_a) { var _b = 1; }")?.is_empty());
+    // A global variable may be read by a file that imports this one.
+    assert!(warnings("var a = 1;")?.is_empty());
+    let code = "fun f() { return g(); } fun g() { return 1; } print f;";
+    assert!(warnings(code)?.is_empty());
+    // Calling itself is not a use, one calling the other is.
+    let code = "fun loop(n) { return loop(n); } fun a() { return b(); } fun b() { return a(); }";
+    assert_eq!(warnings(code)?, ["function `loop` is never called"]);
+    let code = "{ fun inner() {} var f = fun (x) {}; f(1); }";
+    assert_eq!(
+        warnings(code)?,
+        [
+            "parameter `x` is never used",
+            "function `inner` is never called"
+        ]
+    );
+    // Updating a variable reads it.
+    assert!(warnings("{ var a = 0; a += 1; var b = 0; b++; }")?.is_empty());
+    Ok(())
+}
+#[test]
+fn test_redeclaration() -> Result<(), Box<dyn std::error::Error>> {
+    // The text under each label, the first one is the declaration at fault.
+    let labels = |err: &miette::Report| -> Vec<String> {
+        let source = err.source_code().expect("errors have source");
+        err.labels()
+            .expect("errors have labels")
+            .map(|x| {
+                let contents = source.read_span(x.inner(), 0, 0).expect("in the source");
+                format!(
+                    "{}@{}",
+                    String::from_utf8_lossy(contents.data()),
+                    x.offset()
+                )
+            })
+            .collect()
+    };
+    let err = run("{ var a = 1; var a = 2; }").unwrap_err();
+    assert_eq!(err.to_string(), "`a` is already declared in this scope");
+    assert!(err.chain().nth(1).is_none());
+    assert_eq!(labels(&err), ["a@17", "a@6"]);
+    for code in [
+        "fun f(a) { var a = 1; }",
+        "{ fun f() {} var f = 1; }",
+        "for (var i = 0; i < 1; i++) { var j = 0; var j = 1; }",
+    ] {
+        assert!(run(code).is_err(), "{code} should not resolve");
+    }
+    // Globals may be declared again, locals in an inner scope.
+    assert_eq!(run("var a = 1; var a = 2; print a;")?, "2\n");
+    assert_eq!(run("{ var a = 1; { var a = 2; print a; } }")?, "2\n");
+
+    let warnings = |code: &str| -> Result<Vec<miette::Report>, miette::Report> {
+        let mut scope = analysis::Resolver::new(parser::Parser::new(String::from(code))?);
+        scope.warn_shadow = true;
+        scope.resolve()?;
+        Ok(scope.warnings)
+    };
+    let found = warnings("var a = 1; { var b = 2; fun f(a) { var b = a; } }")?;
+    let shown: Vec<_> = found.iter().map(|x| (x.to_string(), labels(x))).collect();
+    assert_eq!(
+        shown,
+        [
+            (
+                String::from("`a` shadows an outer declaration"),
+                vec![String::from("a@30"), String::from("a@4")]
+            ),
+            (
+                String::from("`b` shadows an outer declaration"),
+                vec![String::from("b@39"), String::from("b@17")]
+            ),
+        ]
+    );
+    assert!(found.iter().all(|x| x.chain().nth(1).is_none()));
+    assert!(warnings("{ var a = 1; } { var a = 2; }")?.is_empty());
+    Ok(())
+}
+fn run(code: &str) -> Result<String, miette::Report> {
+    let mut scope = analysis::Resolver::new(parser::Parser::new(String::from(code))?);
+    let (program, resolution) = scope.resolve()?;
+    output(&program, &resolution)
+}
+// Runs the file at `path` with the files it imports.
+fn run_with_loader(path: &std::path::Path) -> Result<String, miette::Report> {
+    let text = std::fs::read_to_string(path).expect("test files are written first");
+    let (program, resolution) = loader::Loader::new().load(path, text)?;
+    output(&program, &resolution)
+}
+// What running a resolved program prints.
+fn output(
+    program: &std::rc::Rc<parser::Ast>,
+    resolution: &std::rc::Rc<analysis::Resolution>,
+) -> Result<String, miette::Report> {
+    let mut runtime = interpreter::Interpreter::with_output(vec![]);
+    runtime.interpret(program, resolution)?;
+    Ok(String::from_utf8(runtime.out).expect("print only writes utf8"))
+}
+#[test]
+fn test_print_output() -> Result<(), Box<dyn std::error::Error>> {
+    assert_eq!(run("print 1 + 2; print \"hi\";")?, "3\nhi\n");
+    assert_eq!(run("var a = 1; var b = 2; print a + b;")?, "3\n");
+    for code in ["print 1", "var x = print 1;", "print;"] {
+        let mut parse = parser::Parser::new(String::from(code))?;
+        assert!(parse.parse_program().is_err(), "{code} should not parse");
+    }
+    Ok(())
+}
+#[test]
+fn test_resolved_locals() -> Result<(), Box<dyn std::error::Error>> {
+    use analysis::Local;
+    let code = "var g = 0; { var a = 1; var b = 2; { var c = b; print a + c + g; } }";
+    let (program, resolution) = analysis::Resolver::new(parser::Parser::new(code)?).resolve()?;
+    let mut ids: Vec<_> = resolution.locals.keys().copied().collect();
+    ids.sort_by_key(|x| program.span(*x).start);
+    let locals: Vec<(&str, Local)> = ids
+        .iter()
+        .map(|x| (program.text(*x), resolution.locals[x]))
+        .collect();
+    let local = |depth, slot| Local { depth, slot };
+    // The declarations of `a`, `b` and `c`, then the reads, `g` is a global.
+    assert_eq!(
+        locals,
+        [
+            ("a", local(0, 0)),
+            ("b", local(0, 1)),
+            ("c", local(0, 0)),
+            ("b", local(1, 1)),
+            ("a", local(1, 0)),
+            ("c", local(0, 0)),
+        ]
+    );
+
+    // A function sees the variables of where it was declared, not where it is called.
+    let code = "var a = \"global\"; { fun show() { print a; } show(); var a = \"block\"; show(); print a; }";
+    assert_eq!(run(code)?, "global\nglobal\nblock\n");
+    let code = "fun counter() { var n = 0; return fun () { n++; return n; }; }
+var c = counter(); c(); var d = counter(); print c(); print d();";
+    assert_eq!(run(code)?, "2\n1\n");
+    // Top level functions may call each other whatever order they are declared in.
+    let code = "fun even(n) { if (n == 0) { return true; } return odd(n - 1); }
+fun odd(n) { if (n == 0) { return false; } return even(n - 1); }
+print even(10);";
+    assert_eq!(run(code)?, "true\n");
+    let code = "{ fun fib(n) { if (n < 2) { return n; } return fib(n - 1) + fib(n - 2); } print fib(10); }";
+    assert_eq!(run(code)?, "55\n");
+    let code = "var fs = []; for (var i = 0; i < 2; i++) { var j = i; fs = [fs, fun () { return j; }]; } print fs[1]() + fs[0][1]();";
+    assert_eq!(run(code)?, "1\n");
+    for code in [
+        "fun f() { return missing; }",
+        "{ fun f() { return later; } var later = 1; }",
+        "fun f(a) {} f(1, 2);",
+    ] {
+        assert!(run(code).is_err(), "{code} should not resolve");
+    }
+    Ok(())
+}
+#[test]
+fn test_function_captures() -> Result<(), Box<dyn std::error::Error>> {
+    use analysis::Local;
+    // Each function by its name, with what it captures.
+    let captures = |code: &str| -> Result<Vec<(String, Vec<Local>)>, miette::Report> {
+        let (program, resolution) =
+            analysis::Resolver::new(parser::Parser::new(code)?).resolve()?;
+        let mut output: Vec<_> = resolution
+            .captures
+            .iter()
+            .filter(|(_, locals)| !locals.is_empty())
+            .map(|(id, locals)| {
+                let name = match &program[*id] {
+                    parser::Tree::Fun { name, .. } => program.text(*name).to_string(),
+                    _ => String::from("lambda"),
+                };
+                (name, locals.clone())
+            })
+            .collect();
+        output.sort_by(|x, y| x.0.cmp(&y.0));
+        Ok(output)
+    };
+    let local = |depth, slot| Local { depth, slot };
+    let code = "fun counter() { var n = 0; return fun () { n++; return n; }; }";
+    assert_eq!(
+        captures(code)?,
+        [(String::from("lambda"), vec![local(0, 0)])]
+    );
+    // Globals are not captured, a function declared in a block captures itself
+    // to call itself.
+    let code = "var g = 1; { fun fib(n) { if (n < 2) { return n + g; } return fib(n - 1); } }";
+    assert_eq!(captures(code)?, [(String::from("fib"), vec![local(0, 0)])]);
+    // A variable an inner function uses is captured by the outer one as well.
+    let code =
+        "{ var a = 1; var b = 2; fun outer() { fun inner() { return b + a; } return inner; } }";
+    assert_eq!(
+        captures(code)?,
+        [
+            (String::from("inner"), vec![local(1, 1), local(1, 0)]),
+            (String::from("outer"), vec![local(0, 1), local(0, 0)]),
+        ]
+    );
+    // A function that captures nothing closes over the globals, not its block.
+    let code = "var h; { var a = 1; fun f() { return 2; } h = f; }";
+    let (program, resolution) = analysis::Resolver::new(parser::Parser::new(code)?).resolve()?;
+    let mut runtime = interpreter::Interpreter::with_output(vec![]);
+    runtime.interpret(&program, &resolution)?;
+    let Some(interpreter::Value::Function(h)) = runtime.env.borrow().get("h") else {
+        panic!("`h` should hold `f`");
+    };
+    assert!(std::rc::Rc::ptr_eq(&h.closure, &runtime.globals));
+    Ok(())
+}
+#[test]
+fn test_own_initializer() -> Result<(), Box<dyn std::error::Error>> {
+    let err = run("{ var a = 1; { var a = a + 1; } }").unwrap_err();
+    let label = err
+        .labels()
+        .and_then(|mut x| x.next())
+        .expect("errors have a label");
+    // The label is on the read, not on the declaration.
+    assert_eq!(label.offset(), 23);
+    assert_eq!(label.len(), 1);
+    assert!(run("fun f() { var b = [b]; }").is_err());
+    // Only a local, a global may already have a value.
+    assert_eq!(run("var a = 1; var a = a + 1; print a;")?, "2\n");
+    let code = "{ var a = 1; { var b = a; var a = b * 10; print a; } print a; }";
+    assert_eq!(run(code)?, "10\n1\n");
+    let code =
+        "{ var f = fun (n) { if (n == 0) { return 0; } return f(n - 1) + n; }; print f(3); }";
+    assert_eq!(run(code)?, "6\n");
+    Ok(())
+}
+#[test]
+fn test_context_errors() -> Result<(), Box<dyn std::error::Error>> {
+    let resolve = |code: &str| -> Result<(), miette::Report> {
+        analysis::Resolver::new(parser::Parser::new(code)?).resolve()?;
+        Ok(())
+    };
+    let code = "class A { fun init() { this.x = 1; return; } fun get() { return this.x; } }
+class B < A { fun get() { var f = fun () { return super.get(); }; return f(); } }";
+    resolve(code)?;
+    let program = parser::Parser::new(code)?.parse_program()?;
+    assert!(
+        program
+            .sexpr(program.program[1])
+            .to_string()
+            .starts_with("(class B A (group")
+    );
+    for (code, message) in [
+        ("return 1;", "`return` can only be used inside a function"),
+        ("{ return; }", "`return` can only be used inside a function"),
+        ("print this;", "`this` can only be used inside a class"),
+        (
+            "fun f() { return this; }",
+            "`this` can only be used inside a class",
+        ),
+        (
+            "fun f() { super.g(); }",
+            "`super` can only be used inside a class",
+        ),
+        (
+            "class A { fun g() { super.g(); } }",
+            "`super` can not be used in a class without a superclass",
+        ),
+        (
+            "class A { fun init() { return 1; } }",
+            "`init` can not return a value",
+        ),
+        ("class A < A {}", "a class can not inherit from itself"),
+    ] {
+        let err = resolve(code).expect_err(code);
+        assert!(err.to_string().starts_with(message), "{code}: {err}");
+    }
+    // Only a method called `init` is an initializer.
+    resolve("class A { fun make() { return 1; } } fun init() { return 1; }")?;
+    Ok(())
+}
+#[test]
+fn test_break_continue() -> Result<(), Box<dyn std::error::Error>> {
+    let code = "var i = 0; while (i < 10) { i = i + 1; if (i == 2) { continue; } if (i == 4) { break; } print i; }";
+    assert_eq!(run(code)?, "1\n3\n");
+    // `continue` in a `for` still runs the increment.
+    let code = "var i = 0; for (i = 0; i < 4; i = i + 1) { if (i == 1) { continue; } print i; }";
+    assert_eq!(run(code)?, "0\n2\n3\n");
+    let code = "var i = 0; while (true) { while (true) { break; } i = i + 1; if (i == 3) { break; } } print i;";
+    assert_eq!(run(code)?, "3\n");
+    for code in [
+        "break;",
+        "{ continue; }",
+        "if (true) { break; }",
+        "while (true) { var f = fun () { break; }; }",
+    ] {
+        assert!(run(code).is_err(), "{code} should not resolve");
+    }
+    let program =
+        parser::Parser::new(String::from("while (x) { break; continue; }"))?.parse_program()?;
+    assert_eq!(sexprs(&program), ["(while x (group (break) (continue)))"]);
+    assert!(
+        parser::Parser::new(String::from("while (x) { break }"))?
+            .parse_program()
+            .is_err()
+    );
+    Ok(())
+}
+#[test]
+fn test_for_loops() -> Result<(), Box<dyn std::error::Error>> {
+    let code = "for (var i = 0; i < 3; i = i + 1) print i;";
+    assert_eq!(run(code)?, "0\n1\n2\n");
+    // The initializer is scoped to the loop.
+    let code = "var i = 10; for (var i = 0; i < 2; i++) {} print i;";
+    assert_eq!(run(code)?, "10\n");
+    let code = "var i = 0; for (; i < 2;) { print i; i++; }";
+    assert_eq!(run(code)?, "0\n1\n");
+    let code = "var i = 0; for (;;) { i++; if (i == 5) { break; } } print i;";
+    assert_eq!(run(code)?, "5\n");
+    let code = "for (var i = 0; i < 4; i++) { if (i % 2 == 0) { continue; } print i; }";
+    assert_eq!(run(code)?, "1\n3\n");
+
+    let code = "for (var i = 0; i < 2; i++) print i; for (;;) x; for (i = 0; ; ) {}";
+    let program = parser::Parser::new(code)?.parse_program()?;
+    assert_eq!(
+        sexprs(&program),
+        [
+            "(for (define i 0) (< i 2) (post++ i) (print i))",
+            "(for #nil #nil #nil (expr x))",
+            "(for (= i 0) #nil #nil (group))",
+        ]
+    );
+    assert_eq!(
+        sexprs(&lower::lower(program)),
+        [
+            "(group (define i 0) (while (< i 2) (print i) (post++ i)))",
+            "(while true (expr x))",
+            "(group (= i 0) (while true (group)))",
+        ]
+    );
+    for code in [
+        "for (var i = 0 i < 2;) {}",
+        "for (;; i++ {}",
+        "for (; i < 2) {}",
+        "for var i = 0; i < 2; {}",
+    ] {
+        assert!(
+            parser::Parser::new(code)?.parse_program().is_err(),
+            "{code} should not parse"
+        );
+    }
+    Ok(())
+}
+#[test]
+fn test_compound_assignment() -> Result<(), Box<dyn std::error::Error>> {
+    let code = "var a = 10; a += 5; print a; a -= 3; print a; a *= 2; print a; a /= 4; print a; a %= 4; print a;";
+    assert_eq!(run(code)?, "15\n12\n24\n6\n2\n");
+    let code = "var a = 1; print a++; print a; print ++a; print a--; print --a;";
+    assert_eq!(run(code)?, "1\n2\n3\n3\n1\n");
+    // The index of the target is only evaluated once.
+    let code = "var i = 0; var xs = [1, 2]; xs[i++] += 10; xs[1]++; print xs; print i;";
+    assert_eq!(run(code)?, "[11, 3]\n1\n");
+    let code =
+        "var s = \"a\"; s += \"b\"; print s; var n = 0; for (n = 0; n < 3; n++) { print n; }";
+    assert_eq!(run(code)?, "ab\n0\n1\n2\n");
+    assert!(run("var s = \"a\"; s++;").is_err());
+    assert!(run("b += 1;").is_err());
+    for code in [
+        "1 += 2;",
+        "var a = 1; (a) += 2;",
+        "a + 1 -= 2;",
+        "++1;",
+        "f()++;",
+        "a++++;",
+    ] {
+        let mut parse = parser::Parser::new(String::from(code))?;
+        assert!(parse.parse_program().is_err(), "{code} should not parse");
+    }
+    Ok(())
+}
+#[test]
+fn test_operator_precedence() -> Result<(), Box<dyn std::error::Error>> {
+    let cases = [
+        ("-a + 2;", "(expr (+ (- a) 2))"),
+        ("!a == b;", "(expr (== (! a) b))"),
+        ("a or b and c;", "(expr (or a (and b c)))"),
+        ("a == b and c < d;", "(expr (and (== a b) (< c d)))"),
+        ("a | b ^ c & d;", "(expr (| a (^ b (& c d))))"),
+        ("x & 1 == 0;", "(expr (== (& x 1) 0))"),
+        ("1 << 2 + 3;", "(expr (<< 1 (+ 2 3)))"),
+        ("a % b * c;", "(expr (* (% a b) c))"),
+        ("2 ** 3 ** 2;", "(expr (** 2 (** 3 2)))"),
+        ("-2 ** 2;", "(expr (- (** 2 2)))"),
+        ("2 ** -1;", "(expr (** 2 (- 1)))"),
+        ("a = b += 1 + 2;", "(expr (= a (+= b (+ 1 2))))"),
+    ];
+    for (code, expected) in cases {
+        let program = parser::Parser::new(String::from(code))?.parse_program()?;
+        assert_eq!(sexprs(&program), [expected], "{code}");
+    }
+    Ok(())
+}
+#[test]
+fn test_arithmetic_and_bitwise() -> Result<(), Box<dyn std::error::Error>> {
+    let code =
+        "print 7 % 3; print -7 % 3; print 2 ** 10; print 2 ** 3 ** 2; print -2 ** 2; print -3 + 5;";
+    assert_eq!(run(code)?, "1\n-1\n1024\n512\n-4\n2\n");
+    let code = "print 12 & 10; print 12 | 10; print 12 ^ 10; print 1 << 4; print -16 >> 2; print true and 1 or 2;";
+    assert_eq!(run(code)?, "8\n14\n6\n16\n-4\n1\n");
+    for code in [
+        "print 1.5 & 1;",
+        "print 1 | 0.5;",
+        "print 1 << 64;",
+        "print 1 >> -1;",
+        "print \"a\" ^ 1;",
+    ] {
+        assert!(run(code).is_err(), "{code} should fail");
+    }
+    Ok(())
+}
+#[test]
+fn test_import() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("rlox_import_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib"))?;
+    let write = |name: &str, code: &str| std::fs::write(dir.join(name), code);
+    write(
+        "main.lox",
+        "import \"lib/a.lox\"; import \"lib/b.lox\"; print twice(n);",
+    )?;
+    // `a` imports `b` as well, `b` still only runs once.
+    write(
+        "lib/a.lox",
+        "import \"b.lox\"; fun twice(x) { return x * 2; }",
+    )?;
+    write("lib/b.lox", "var n = 21; print \"b\";")?;
+    assert_eq!(run_with_loader(&dir.join("main.lox"))?, "b\n42\n");
+
+    write("lib/b.lox", "import \"../main.lox\"; var n = 1;")?;
+    let err = run_with_loader(&dir.join("main.lox")).unwrap_err();
+    assert!(err.to_string().starts_with("import cycle: "), "{err}");
+    write("lib/b.lox", "var n = 1; print m;")?;
+    // Diagnostics name the file the error is in.
+    let err = run_with_loader(&dir.join("main.lox")).unwrap_err();
+    let label = err
+        .labels()
+        .and_then(|mut x| x.next())
+        .expect("errors have a label");
+    let contents = err
+        .source_code()
+        .expect("errors have source")
+        .read_span(label.inner(), 0, 0)?;
+    assert_eq!(
+        contents.name(),
+        Some(dir.join("lib/b.lox").to_str().expect("utf8 path"))
+    );
+    write("nested.lox", "{ import \"lib/b.lox\"; }")?;
+    assert!(run_with_loader(&dir.join("nested.lox")).is_err());
+    write("missing.lox", "import \"nope.lox\";")?;
+    assert!(run_with_loader(&dir.join("missing.lox")).is_err());
+    std::fs::remove_dir_all(&dir)?;
+    // Without a loader there is no file to import from.
+    let mut scope =
+        analysis::Resolver::new(parser::Parser::new(String::from("import \"a.lox\";"))?);
+    assert!(scope.resolve().is_err());
+    Ok(())
+}
+#[test]
+fn test_named_diagnostics() -> Result<(), Box<dyn std::error::Error>> {
+    // The file name and the line and column of the first label.
+    let location = |err: miette::Report| -> (Option<String>, usize, usize) {
+        let label = err
+            .labels()
+            .and_then(|mut x| x.next())
+            .expect("errors have a label");
+        let source = err.source_code().expect("errors have source");
+        let contents = source
+            .read_span(label.inner(), 0, 0)
+            .expect("the label is in the source");
+        (
+            contents.name().map(String::from),
+            contents.line() + 1,
+            contents.column() + 1,
+        )
+    };
+    let named = |code: &str| source::Source::new("main.lox", code);
+    let name = Some(String::from("main.lox"));
+    let err = parser::Parser::new(named("var a = 1;\nvar b = @;")).unwrap_err();
+    assert_eq!(location(err), (name.clone(), 2, 9));
+    let err = parser::Parser::new(named("var a = 1;\nvar b = ;"))?
+        .parse_program()
+        .unwrap_err();
+    assert_eq!(location(err), (name.clone(), 2, 9));
+    let err = analysis::Resolver::new(parser::Parser::new(named("var a = 1;\nprint b;"))?)
+        .resolve()
+        .unwrap_err();
+    assert_eq!(location(err), (name.clone(), 2, 7));
+    let (program, resolution) =
+        analysis::Resolver::new(parser::Parser::new(named("print -nil;"))?).resolve()?;
+    let err = interpreter::Interpreter::with_output(vec![])
+        .interpret(&program, &resolution)
+        .unwrap_err();
+    assert_eq!(location(err), (name.clone(), 1, 7));
+    let err = format::format(named("print;")).unwrap_err();
+    assert_eq!(location(err).0, name);
+    let err = sexpr::read(named("(print")).unwrap_err();
+    assert_eq!(location(err).0, name);
+    // Code that is not from a file has no name.
+    let err = parser::Parser::new("@").unwrap_err();
+    assert_eq!(location(err), (None, 1, 1));
+    // A string the file ends in points at its opening quote.
+    let err = parser::Parser::new(named("var a = 1;\nvar s = \"abc")).unwrap_err();
+    assert_eq!(err.to_string(), "Unterminated string");
+    assert_eq!(location(err), (name.clone(), 2, 9));
+    // A NUL byte is not the end of the file.
+    let err = parser::Parser::new(named("var a = 1;\0 var b = ;")).unwrap_err();
+    assert_eq!(err.to_string(), "invalid token found");
+    assert_eq!(location(err), (name.clone(), 1, 11));
+    Ok(())
+}
+// A small xorshift generator so the fuzz tests are reproducible without extra dependencies.
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+fn parses_without_panicking(code: &str) {
+    let result = std::panic::catch_unwind(|| {
+        if let Ok(mut parse) = parser::Parser::new(String::from(code)) {
+            let _ = parse.parse_program();
+        }
+    });
+    assert!(result.is_ok(), "the parser panicked on {code:?}");
+}
+#[test]
+fn fuzz_random_tokens() {
+    const LEXEMES: &[&str] = &[
+        "(", ")", "[", "]", "{", "}", ",", ".", "-", "+", ";", "/", "*", "!", "!=", "=", "==", ">",
+        ">=", "<", "<=", "and", "class", "else", "false", "for", "fun", "if", "nil", "or", "print",
+        "return", "super", "this", "true", "var", "while", "a", "b", "foo", "0", "2.5", "\"s\"",
+        "\"open", "é", "\n",
+    ];
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..20_000 {
+        let len = rng.below(40);
+        let mut code = String::new();
+        for _ in 0..len {
+            code.push_str(LEXEMES[rng.below(LEXEMES.len())]);
+            if rng.below(4) != 0 {
+                code.push(' ');
+            }
+        }
+        parses_without_panicking(&code);
+    }
+}
+#[test]
+fn fuzz_random_chars() {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..5_000 {
+        let len = rng.below(30);
+        let code: String = (0..len)
+            .filter_map(|_| match rng.below(3) {
+                0 => char::from_u32(rng.below(0x11_0000) as u32),
+                _ => char::from_u32(rng.below(0x80) as u32),
+            })
+            .collect();
+        parses_without_panicking(&code);
+    }
+}
+#[test]
+fn fuzz_deep_nesting() {
+    for open in ["(", "{", "[", "-", "!", "fun(){", "print ", "a = ", "f("] {
+        parses_without_panicking(&open.repeat(100_000));
+    }
+}
+#[test]
+fn test_reported_parser_panics() -> Result<(), Box<dyn std::error::Error>> {
+    let mut parse = parser::Parser::new(String::from("!x; a[0]; -f(1);"))?;
+    assert!(parse.parse_program().is_ok());
+    for code in ["super", "var a =", "f(1", "(1", ")", "é = 1;"] {
+        parses_without_panicking(code);
+    }
+    let mut parse = parser::Parser::new(String::from("a"))?;
+    parse.current();
+    Ok(())
+}
+#[test]
+fn test_format() -> Result<(), Box<dyn std::error::Error>> {
+    let code = "
+// leading comment
+var a=1;var b   =   -a+2; // trailing
+fun   foo(x,y){
+if(x<y){print x;}else{print y;}
+
+
+  // own line
+for(a;a<2;a=a+1){print a;}
+return fun(z){return z*2;};
+}
+var xs=[1,2,[3]];xs[0]=!true;
+var c=- -a;var d=- --a;var e=a+ ++b;var f=a- -b;
+apply(fun (q) {return q;},2);
+{}
+";
+    let output = format_same_tree(code)?;
+    insta::assert_snapshot!(output);
+    assert_eq!(format::format(&output)?, output);
+    assert!(format::format("var a = ;").is_err());
+    Ok(())
+}
+#[test]
+fn test_format_idempotent() -> Result<(), Box<dyn std::error::Error>> {
+    let mut rng = Rng(0x5851_f42d_4c95_7f2d);
+    const LINES: &[&str] = &[
+        "var a = 1;",
+        "// note",
+        "print a;",
+        "if (a) { print 1; } else { print 2; }",
+        "{",
+        "}",
+        "fun f(x) { return x; } // end",
+        "while (a < 2) { a = a + 1; }",
+        "var b = - -a;",
+        "var c = - --a;",
+        "var d = a + ++a;",
+        "",
+    ];
+    for _ in 0..500 {
+        let mut code = String::new();
+        let mut open = 0;
+        for _ in 0..rng.below(12) {
+            let line = LINES[rng.below(LINES.len())];
+            match line {
+                "{" => open += 1,
+                "}" if open == 0 => continue,
+                "}" => open -= 1,
+                _ => {}
+            }
+            code.push_str(line);
+            match line.contains("//") {
+                true => code.push('\n'),
+                false => code.push_str(["\n", " ", "\n\n"][rng.below(3)]),
+            }
+        }
+        code.push_str(&"}".repeat(open));
+        let once = format_same_tree(&code)?;
+        assert_eq!(
+            format::format(&once)?,
+            once,
+            "formatting {code:?} is not stable"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_format_same_tree() -> Result<(), Box<dyn std::error::Error>> {
+    let fixtures = [
+        "var a=- - -1;var b=-(-a);var c=!!true;var d=!-a;",
+        "var e=- --a;var f=- -a--;var g=a-- - -b;var h=a- - --b;",
+        "var i=a+ ++b;var j=a++ + ++b;var k=-a*-(b- -c);",
+        "fun f(x){return - -x;}print f(- -1) - -f(2);",
+        "for(var i=0;i<2;i++){print -i;}while(a){a-=1;}",
+    ];
+    for code in fixtures {
+        let once = format_same_tree(code)?;
+        assert_eq!(format::format(&once)?, once);
+    }
+    Ok(())
+}
+
+// Formats `code`, checking that the output parses to the same tree as the input.
+fn format_same_tree(code: &str) -> Result<String, miette::Report> {
+    let output = format::format(code)?;
+    assert_eq!(
+        sexprs(&parse(&output)?),
+        sexprs(&parse(code)?),
+        "formatting {code:?} as {output:?} changes the program"
+    );
+    Ok(output)
+}
+
+#[test]
+fn test_sexpr() -> Result<(), Box<dyn std::error::Error>> {
+    let cases = [
+        ("var a = 1 + 2 * 3;", "(define a (+ 1 (* 2 3)))"),
+        ("var b;", "(define b)"),
+        ("f(1, g(2));", "(expr (apply f 1 (apply g 2)))"),
+        ("xs[0] = !true;", "(expr (= (index xs 0) (! true)))"),
+        ("print \"hi\";", "(print \"hi\")"),
+        (
+            "fun add(a, b) { return a + b; }",
+            "(fun add (a b) (group (return (+ a b))))",
+        ),
+        (
+            "var k = fun (x) { return x; };",
+            "(define k (lambda (x) (group (return x))))",
+        ),
+        (
+            "if (a < 1) { print nil; } else { a = 1; }",
+            "(if (< a 1) (group (print nil)) (group (expr (= a 1))))",
+        ),
+    ];
+    for (code, expected) in cases {
+        let program = parser::Parser::new(String::from(code))?.parse_program()?;
+        assert_eq!(sexprs(&program).join("\n"), expected);
+    }
+    Ok(())
+}
+
+fn sexprs(ast: &parser::Ast) -> Vec<String> {
+    ast.program
+        .iter()
+        .map(|x| ast.sexpr(*x).to_string())
+        .collect()
+}
+
+#[test]
+fn test_sexpr_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let code = "
+var a = 1.5;
+var xs = [1, \"two\", [nil]];
+fun foo(x, y) {
+    for (a; a < 2; a = a + 1) { print x; }
+    while (y) { y = false; }
+    return fun () { return xs[0]; };
+}
+print foo(a, true)();
+";
+    let program = parser::Parser::new(String::from(code))?.parse_program()?;
+    let printed = sexprs(&program);
+    let read = sexpr::read(printed.join("\n"))?;
+    assert_eq!(sexprs(&read), printed);
+    // Spans point into the S-expression text.
+    let first = read.program[0];
+    assert_eq!(read.text(first), printed[0]);
+
+    // A literal too large for an `f64` reads back as a number, not a variable `inf`.
+    let code = format!("print 1{} - 1;", "0".repeat(400));
+    let printed = sexprs(&parse(&code)?);
+    assert_eq!(printed, ["(print (- #inf 1))"]);
+    let read = std::rc::Rc::new(sexpr::read(printed.join("\n"))?);
+    let mut out = Vec::new();
+    interpreter::Interpreter::with_output(&mut out).interpret(&read, &Default::default())?;
+    assert_eq!(String::from_utf8(out)?, run(&code)?);
+
+    let special = "#nil #error #+ #inf #-inf #nan (class A (group)) (. a b)";
+    let read = sexpr::read(special)?;
+    assert_eq!(sexprs(&read).join(" "), special);
+    for op in parser::Op::ALL {
+        let read = sexpr::read(format!("#{op}"))?;
+        assert_eq!(read[read.program[0]], parser::Tree::Op(op));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sexpr_runs() -> Result<(), Box<dyn std::error::Error>> {
+    let program = std::rc::Rc::new(sexpr::read("(define a 2) (print (* a 21))")?);
+    let mut out = Vec::new();
+    interpreter::Interpreter::with_output(&mut out).interpret(&program, &Default::default())?;
+    assert_eq!(String::from_utf8(out)?, "42\n");
+    for bad in ["(define a", "(nope 1)", "(fun f x x)", ")", "(#+ 1)"] {
+        assert!(sexpr::read(bad).is_err(), "{bad:?} should not be read");
+    }
+    // A string missing its closing quote, which evaluating would slice inside `é`.
+    for bad in ["\"", "\"é", "(print \"a)"] {
+        let err = sexpr::read(bad).unwrap_err();
+        assert_eq!(err.to_string(), "unterminated string, a \" is missing");
+    }
+    Ok(())
+}
+
+#[test]
+fn test_ast_json() -> Result<(), Box<dyn std::error::Error>> {
+    let code = "var s = \"a\tb\";
+fun f(x) { return -x; }
+print f(1.5) == nil;";
+    let program = parser::Parser::new(String::from(code))?.parse_program()?;
+    insta::assert_snapshot!(ast_json::to_json(&program));
+    Ok(())
+}
+
+#[test]
+fn test_visitor() -> Result<(), Box<dyn std::error::Error>> {
+    use parser::{Ast, NodeId};
+    // Collects the names that are read, skipping declarations.
+    struct Reads(Vec<String>);
+    impl visit::Visitor for Reads {
+        fn visit_atom(&mut self, ast: &Ast, id: NodeId, atom: &parser::Atom) {
+            if *atom == parser::Atom::Ident {
+                self.0.push(ast.text(id).to_string());
+            }
+        }
+        fn visit_var(&mut self, ast: &Ast, _: NodeId, _: NodeId, value: Option<NodeId>) {
+            visit::walk_ids(self, ast, value.as_slice());
+        }
+        fn visit_fun(&mut self, ast: &Ast, _: NodeId, _: NodeId, _: &[NodeId], body: NodeId) {
+            self.visit_tree(ast, body);
+        }
+        fn visit_lambda(&mut self, ast: &Ast, _: NodeId, _: &[NodeId], body: NodeId) {
+            self.visit_tree(ast, body);
+        }
+    }
+    let code = "var a = b; fun f(x) { return x + c; } print f(a)[d]; var g = fun (y) { e(); };";
+    let program = parser::Parser::new(String::from(code))?.parse_program()?;
+    let mut reads = Reads(Vec::new());
+    visit::walk_ids(&mut reads, &program, &program.program);
+    assert_eq!(reads.0, ["b", "x", "c", "f", "a", "d", "e"]);
+    Ok(())
+}
+
+#[test]
+fn test_folder() -> Result<(), Box<dyn std::error::Error>> {
+    use parser::{Ast, NodeId, Tree};
+    // Negates every number in place and swaps `+` for a new `-` node.
+    struct Flip;
+    impl visit::Folder for Flip {
+        fn fold_atom(&mut self, ast: &mut Ast, id: NodeId, atom: parser::Atom) -> NodeId {
+            if let parser::Atom::Number(x) = atom {
+                ast.replace(id, Tree::Atom(parser::Atom::Number(-x)));
+            }
+            id
+        }
+        fn fold_non_term(
+            &mut self,
+            ast: &mut Ast,
+            id: NodeId,
+            op: parser::Op,
+            ids: Vec<NodeId>,
+        ) -> NodeId {
+            let ids = visit::fold_ids(self, ast, ids);
+            match op {
+                parser::Op::Plus => ast.push(Tree::NonTerm(parser::Op::Minus, ids), ast.span(id)),
+                _ => {
+                    ast.replace(id, Tree::NonTerm(op, ids));
+                    id
+                }
+            }
+        }
+    }
+    let code = "var a = 1 + 2; fun f(x) { return [x + 3]; } print f(4);";
+    let mut program = parser::Parser::new(String::from(code))?.parse_program()?;
+    let plus = match program[program.program[0]] {
+        Tree::Var(_, Some(x)) => x,
+        _ => unreachable!(),
+    };
+    let ids = std::mem::take(&mut program.program);
+    program.program = visit::fold_ids(&mut Flip, &mut program, ids);
+    assert_eq!(
+        sexprs(&program),
+        [
+            "(define a (- -1 -2))",
+            "(fun f (x) (group (return (list (- x -3)))))",
+            "(print (apply f -4))",
+        ]
+    );
+    // The replaced `+` node is left in the arena, with the same span as its replacement.
+    assert_eq!(program.sexpr(plus).to_string(), "(+ -1 -2)");
+    assert_eq!(program.text(plus), "1 + 2");
+    Ok(())
+}
+
+#[test]
+fn test_trace() -> Result<(), Box<dyn std::error::Error>> {
+    let mut parse = parser::Parser::new("var a = -1;\nprint a + 2;")?;
+    parse.trace = Some(trace::Tracer::capture("parse"));
+    let mut resolver = analysis::Resolver::new(parse);
+    resolver.trace = Some(trace::Tracer::capture("resolve"));
+    resolver.resolve()?;
+    let mut events = resolver.ast.trace.take().and_then(|x| x.captured).unwrap();
+    events.extend(resolver.trace.take().and_then(|x| x.captured).unwrap());
+    insta::assert_snapshot!(events.join("\n"));
+
+    // A failing rule still closes its event.
+    let mut parse = parser::Parser::new("print (1;")?;
+    parse.trace = Some(trace::Tracer::capture("parse"));
+    assert!(parse.parse_program().is_err());
+    let events = parse.trace.and_then(|x| x.captured).unwrap();
+    assert_eq!(events.last().unwrap(), "parse exit statement failed");
+    Ok(())
+}
+
+// A full parse, which an incremental one has to match.
+fn parse(text: &str) -> Result<parser::Ast, miette::Report> {
+    parser::Parser::new(text)?.parse_program()
+}
+
+// Every node of the program with its span, to compare trees that number their nodes differently.
+fn nodes_and_spans(ast: &parser::Ast) -> Vec<(std::ops::Range<usize>, String)> {
+    struct Collect(Vec<(std::ops::Range<usize>, String)>);
+    impl visit::Visitor for Collect {
+        fn visit_tree(&mut self, ast: &parser::Ast, id: parser::NodeId) {
+            self.0.push((ast.span(id), ast.sexpr(id).to_string()));
+            visit::walk_tree(self, ast, id);
+        }
+    }
+    let mut collect = Collect(Vec::new());
+    visit::walk_ids(&mut collect, ast, &ast.program);
+    collect.0
+}
+
+#[test]
+fn test_incremental_reparse() -> Result<(), Box<dyn std::error::Error>> {
+    use incremental::{Document, Edit};
+    let code = "var a = 1;
+fun f(x) { return x + a; }
+print f(2);
+var b = \"s\";
+";
+    let document = Document::parse(source::Source::new("main.lox", code))?;
+    let at = |find: &str, text: &str| {
+        let start = code.find(find).unwrap();
+        Edit {
+            range: start..start + find.len(),
+            text: text.to_string(),
+        }
+    };
+    // Only the edited statement is parsed again, the others are shared and moved.
+    let (edited, changed) = document.edit(&at("x + a", "x * a - 10"))?;
+    let text = &edited.source().text;
+    assert_eq!(changed.len(), 1);
+    assert_eq!(&text[changed[0].clone()], "fun f(x) { return x * a - 10; }");
+    assert_eq!(edited.source().name, "main.lox");
+    let full = parser::Parser::new(text.as_str())?.parse_program()?;
+    assert_eq!(nodes_and_spans(&edited.ast()), nodes_and_spans(&full));
+    let shared: Vec<bool> = document
+        .statements()
+        .zip(edited.statements())
+        .map(|(x, y)| std::rc::Rc::ptr_eq(&x.ast, &y.ast))
+        .collect();
+    assert_eq!(shared, [true, false, true, true]);
+    let starts: Vec<usize> = edited.statements().map(|x| x.start).collect();
+    assert_eq!(starts, [0, 11, 43, 55]);
+    // An edit outside of the text, or inside a character, is an error.
+    let backwards = std::ops::Range { start: 5, end: 4 };
+    for range in [0..200, backwards, 201..201] {
+        let edit = Edit {
+            range,
+            text: String::new(),
+        };
+        assert!(document.edit(&edit).is_err(), "{edit:?}");
+    }
+    let err = Document::parse("print \"é\";")?
+        .edit(&Edit {
+            range: 8..8,
+            text: String::new(),
+        })
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "the edit of bytes 8..8 is not within the text or splits a character"
+    );
+
+    let edits = [
+        at("print f(2);", ""),
+        at("\n", "\nvar c = a;\n"),
+        at("1;", "1; print a"),
+        at("}", "} else"),
+        at("\"s\"", "\"s\" + \"t\""),
+        at("print", "pr"),
+        at("var b", "// var b"),
+        at(code, "print 1;"),
+    ];
+    for edit in edits {
+        let mut text = code.to_string();
+        text.replace_range(edit.range.clone(), &edit.text);
+        match (document.edit(&edit), parse(&text)) {
+            (Ok((edited, _)), Ok(full)) => {
+                assert_eq!(edited.source().text, text);
+                assert_eq!(
+                    nodes_and_spans(&edited.ast()),
+                    nodes_and_spans(&full),
+                    "{text}"
+                );
+            }
+            (Err(_), Err(_)) => {}
+            (edited, full) => panic!("{text:?}: {:?} but {:?}", edited.is_ok(), full.is_ok()),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn fuzz_incremental_reparse() -> Result<(), Box<dyn std::error::Error>> {
+    use incremental::{Document, Edit};
+    const LEXEMES: &[&str] = &[
+        "var a = 1;",
+        "print a;",
+        "fun f(x) { return x; }",
+        "{ a = a + 1; }",
+        "if (a) print 1;",
+        "else",
+        "while (a) a = a - 1;",
+        "f(1.5)",
+        "\"s\"",
+        "1.",
+        "5",
+        ";",
+        "}",
+        "(",
+        "//",
+        "\n",
+        " ",
+        "=",
+        "a",
+        "é",
+    ];
+    let mut rng = Rng(0x5851_f42d_4c95_7f2d);
+    for _ in 0..2_000 {
+        let mut text: String = (0..rng.below(12))
+            .map(|_| LEXEMES[rng.below(LEXEMES.len())])
+            .collect();
+        let Ok(mut document) = Document::parse(text.as_str()) else {
+            continue;
+        };
+        for _ in 0..5 {
+            let boundary = |x: usize| (0..=x).rev().find(|x| text.is_char_boundary(*x)).unwrap();
+            let start = boundary(rng.below(text.len() + 1));
+            let end = boundary((start + rng.below(8)).min(text.len())).max(start);
+            let edit = Edit {
+                range: start..end,
+                text: LEXEMES[rng.below(LEXEMES.len())].to_string(),
+            };
+            text.replace_range(edit.range.clone(), &edit.text);
+            match (document.edit(&edit), parse(&text)) {
+                (Ok((edited, _)), Ok(full)) => {
+                    assert_eq!(
+                        nodes_and_spans(&edited.ast()),
+                        nodes_and_spans(&full),
+                        "{text:?}"
+                    );
+                    document = edited;
+                }
+                (Err(_), Err(_)) => break,
+                (edited, full) => panic!("{text:?}: {:?} but {:?}", edited.is_ok(), full.is_ok()),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Counting every allocation slows down the whole test binary, so it is only
+// built for `cargo test --release --features measure measure_parse -- --nocapture`.
+#[cfg(feature = "measure")]
+mod measure {
+    use super::*;
+
+    // Counts live heap bytes so `measure_parse` can report what a parsed tree costs.
+    struct Counting;
+    static ALLOCATED: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    unsafe impl std::alloc::GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            ALLOCATED.fetch_add(layout.size(), std::sync::atomic::Ordering::Relaxed);
+            unsafe { std::alloc::System.alloc(layout) }
+        }
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            ALLOCATED.fetch_sub(layout.size(), std::sync::atomic::Ordering::Relaxed);
+            unsafe { std::alloc::System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: Counting = Counting;
+
+    #[test]
+    fn measure_parse() -> Result<(), Box<dyn std::error::Error>> {
+        use std::sync::atomic::Ordering;
+        let mut code = String::new();
+        for i in 0..20_000 {
+            code.push_str(&format!(
+                "fun f{i}(a, b) {{ var c = a * {i} + b; if (c > 10) {{ print \"big\"; }} else {{ c = c - 1; }} return [c, a(b)]; }}\nvar v{i} = f{i}(1, 2)[0];\n"
+            ));
+        }
+        let start = std::time::Instant::now();
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        let program = parser::Parser::new(code.clone())?.parse_program()?;
+        let bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+        println!(
+            "{} bytes of source, parsed in {:?}, tree holds {} bytes",
+            code.len(),
+            start.elapsed(),
+            bytes - code.len(),
+        );
+        drop(program);
+        Ok(())
+    }
+}
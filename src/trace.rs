@@ -0,0 +1,101 @@
+//! Opt-in tracing of the parser and the resolver, `rlox --trace=parse,resolve`.
+use crate::parser::{Ast, NodeId, Tree};
+use crate::source::Source;
+
+/// Writes an indented line to stderr when a pass enters or leaves a rule or a
+/// node, with the line and column of where it is in the source:
+///
+/// ```text
+/// parse enter statement at 1:1
+/// parse   enter expression at 1:9
+/// parse   exit expression + 1:9..1:14
+/// parse exit statement define 1:1..1:15
+/// ```
+#[derive(Debug)]
+pub struct Tracer {
+    pass: &'static str,
+    depth: usize,
+    //Keeps the events instead of writing them, for tests
+    pub captured: Option<Vec<String>>,
+}
+
+impl Tracer {
+    pub fn new(pass: &'static str) -> Self {
+        Self {
+            pass,
+            depth: 0,
+            captured: None,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn capture(pass: &'static str) -> Self {
+        Self {
+            captured: Some(Vec::new()),
+            ..Self::new(pass)
+        }
+    }
+
+    pub fn enter(&mut self, what: &str, source: &Source, at: usize) {
+        let (line, column) = position(source, at);
+        self.emit(format!("enter {what} at {line}:{column}"));
+        self.depth += 1;
+    }
+
+    /// Ends the innermost `enter`, with the node it produced or `None` if it failed.
+    pub fn exit(&mut self, what: &str, node: Option<(&Ast, NodeId)>) {
+        self.depth = self.depth.saturating_sub(1);
+        let Some((ast, id)) = node else {
+            self.emit(format!("exit {what} failed"));
+            return;
+        };
+        let span = ast.span(id);
+        let (line, column) = position(&ast.source, span.start);
+        let (end_line, end_column) = position(&ast.source, span.end);
+        // The resolver names its events after the node already.
+        let head = head(ast, id);
+        let node = match head == what {
+            true => String::new(),
+            false => format!("{head} "),
+        };
+        self.emit(format!(
+            "exit {what} {node}{line}:{column}..{end_line}:{end_column}"
+        ));
+    }
+
+    fn emit(&mut self, event: String) {
+        let line = format!(
+            "{} {:indent$}{event}",
+            self.pass,
+            "",
+            indent = 2 * self.depth
+        );
+        match &mut self.captured {
+            Some(events) => events.push(line),
+            None => eprintln!("{line}"),
+        }
+    }
+}
+
+/// What a node is called in an event, the head of its S-expression or the
+/// whole of it for a leaf.
+pub fn head(ast: &Ast, id: NodeId) -> String {
+    let head = match &ast[id] {
+        Tree::ExprStatment(_) => "expr",
+        Tree::NonTerm(op, _) => op.name(),
+        Tree::Var(..) => "define",
+        Tree::Call { .. } => "apply",
+        Tree::Fun { .. } => "fun",
+        Tree::Lambda { .. } => "lambda",
+        Tree::Nil | Tree::Op(_) | Tree::Atom(_) => return ast.sexpr(id).to_string(),
+    };
+    head.to_string()
+}
+
+// The 1-based line and column, counted in characters, of a byte offset.
+fn position(source: &Source, offset: usize) -> (usize, usize) {
+    let before = &source.text[..offset.min(source.text.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |x| x + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
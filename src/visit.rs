@@ -0,0 +1,222 @@
+//! Generic traversal of an [`Ast`], so passes only write the cases they care about.
+//!
+//! [`Visitor`] reads a tree, every `visit_*` method defaults to the matching
+//! `walk_*` function which visits the children in source order. Override a
+//! method to act on that node kind, and call the `walk_*` function from it to
+//! keep descending. Each method gets the node's id, so results can go in a
+//! side table keyed by it.
+//!
+//! [`Folder`] rewrites a tree in place the same way, each `fold_*` method
+//! defaults to the `fold_*` function of this module that folds the children
+//! and stores the node back with their new ids. A method returns the id that
+//! takes the node's place, which may be a node it just pushed.
+use crate::parser::{Ast, Atom, NodeId, Op, Tree};
+
+pub trait Visitor {
+    fn visit_tree(&mut self, ast: &Ast, id: NodeId) {
+        walk_tree(self, ast, id)
+    }
+    fn visit_nil(&mut self, _ast: &Ast, _id: NodeId) {}
+    fn visit_atom(&mut self, _ast: &Ast, _id: NodeId, _atom: &Atom) {}
+    fn visit_op(&mut self, _ast: &Ast, _id: NodeId, _op: Op) {}
+    fn visit_expr_statment(&mut self, ast: &Ast, _id: NodeId, ids: &[NodeId]) {
+        walk_ids(self, ast, ids)
+    }
+    fn visit_var(&mut self, ast: &Ast, _id: NodeId, name: NodeId, value: Option<NodeId>) {
+        walk_var(self, ast, name, value)
+    }
+    fn visit_call(&mut self, ast: &Ast, _id: NodeId, callee: NodeId, arguments: &[NodeId]) {
+        walk_call(self, ast, callee, arguments)
+    }
+    fn visit_non_term(&mut self, ast: &Ast, _id: NodeId, _op: Op, ids: &[NodeId]) {
+        walk_ids(self, ast, ids)
+    }
+    fn visit_fun(
+        &mut self,
+        ast: &Ast,
+        _id: NodeId,
+        name: NodeId,
+        parameters: &[NodeId],
+        body: NodeId,
+    ) {
+        walk_fun(self, ast, name, parameters, body)
+    }
+    fn visit_lambda(&mut self, ast: &Ast, _id: NodeId, parameters: &[NodeId], body: NodeId) {
+        walk_lambda(self, ast, parameters, body)
+    }
+}
+
+/// Calls the `visit_*` method for the kind of node `id`.
+pub fn walk_tree<V: Visitor + ?Sized>(visitor: &mut V, ast: &Ast, id: NodeId) {
+    match &ast[id] {
+        Tree::Nil => visitor.visit_nil(ast, id),
+        Tree::Atom(atom) => visitor.visit_atom(ast, id, atom),
+        Tree::Op(op) => visitor.visit_op(ast, id, *op),
+        Tree::ExprStatment(ids) => visitor.visit_expr_statment(ast, id, ids),
+        Tree::Var(name, value) => visitor.visit_var(ast, id, *name, *value),
+        Tree::Call { callee, arguments } => visitor.visit_call(ast, id, *callee, arguments),
+        Tree::NonTerm(op, ids) => visitor.visit_non_term(ast, id, *op, ids),
+        Tree::Fun {
+            name,
+            parameters,
+            body,
+        } => visitor.visit_fun(ast, id, *name, parameters, *body),
+        Tree::Lambda { parameters, body } => visitor.visit_lambda(ast, id, parameters, *body),
+    }
+}
+
+pub fn walk_ids<V: Visitor + ?Sized>(visitor: &mut V, ast: &Ast, ids: &[NodeId]) {
+    for id in ids {
+        visitor.visit_tree(ast, *id);
+    }
+}
+
+pub fn walk_var<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    ast: &Ast,
+    name: NodeId,
+    value: Option<NodeId>,
+) {
+    visitor.visit_tree(ast, name);
+    if let Some(value) = value {
+        visitor.visit_tree(ast, value);
+    }
+}
+
+pub fn walk_call<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    ast: &Ast,
+    callee: NodeId,
+    arguments: &[NodeId],
+) {
+    visitor.visit_tree(ast, callee);
+    walk_ids(visitor, ast, arguments);
+}
+
+pub fn walk_fun<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    ast: &Ast,
+    name: NodeId,
+    parameters: &[NodeId],
+    body: NodeId,
+) {
+    visitor.visit_tree(ast, name);
+    walk_lambda(visitor, ast, parameters, body);
+}
+
+pub fn walk_lambda<V: Visitor + ?Sized>(
+    visitor: &mut V,
+    ast: &Ast,
+    parameters: &[NodeId],
+    body: NodeId,
+) {
+    walk_ids(visitor, ast, parameters);
+    visitor.visit_tree(ast, body);
+}
+
+pub trait Folder {
+    fn fold_tree(&mut self, ast: &mut Ast, id: NodeId) -> NodeId {
+        fold_tree(self, ast, id)
+    }
+    fn fold_nil(&mut self, _ast: &mut Ast, id: NodeId) -> NodeId {
+        id
+    }
+    fn fold_atom(&mut self, _ast: &mut Ast, id: NodeId, _atom: Atom) -> NodeId {
+        id
+    }
+    fn fold_op(&mut self, _ast: &mut Ast, id: NodeId, _op: Op) -> NodeId {
+        id
+    }
+    fn fold_expr_statment(&mut self, ast: &mut Ast, id: NodeId, ids: Vec<NodeId>) -> NodeId {
+        let ids = fold_ids(self, ast, ids);
+        ast.replace(id, Tree::ExprStatment(ids));
+        id
+    }
+    fn fold_var(
+        &mut self,
+        ast: &mut Ast,
+        id: NodeId,
+        name: NodeId,
+        value: Option<NodeId>,
+    ) -> NodeId {
+        let name = self.fold_tree(ast, name);
+        let value = value.map(|x| self.fold_tree(ast, x));
+        ast.replace(id, Tree::Var(name, value));
+        id
+    }
+    fn fold_call(
+        &mut self,
+        ast: &mut Ast,
+        id: NodeId,
+        callee: NodeId,
+        arguments: Vec<NodeId>,
+    ) -> NodeId {
+        let callee = self.fold_tree(ast, callee);
+        let arguments = fold_ids(self, ast, arguments);
+        ast.replace(id, Tree::Call { callee, arguments });
+        id
+    }
+    fn fold_non_term(&mut self, ast: &mut Ast, id: NodeId, op: Op, ids: Vec<NodeId>) -> NodeId {
+        let ids = fold_ids(self, ast, ids);
+        ast.replace(id, Tree::NonTerm(op, ids));
+        id
+    }
+    fn fold_fun(
+        &mut self,
+        ast: &mut Ast,
+        id: NodeId,
+        name: NodeId,
+        parameters: Vec<NodeId>,
+        body: NodeId,
+    ) -> NodeId {
+        let name = self.fold_tree(ast, name);
+        let parameters = fold_ids(self, ast, parameters);
+        let body = self.fold_tree(ast, body);
+        let tree = Tree::Fun {
+            name,
+            parameters,
+            body,
+        };
+        ast.replace(id, tree);
+        id
+    }
+    fn fold_lambda(
+        &mut self,
+        ast: &mut Ast,
+        id: NodeId,
+        parameters: Vec<NodeId>,
+        body: NodeId,
+    ) -> NodeId {
+        let parameters = fold_ids(self, ast, parameters);
+        let body = self.fold_tree(ast, body);
+        ast.replace(id, Tree::Lambda { parameters, body });
+        id
+    }
+}
+
+/// Calls the `fold_*` method for the kind of node `id`.
+pub fn fold_tree<F: Folder + ?Sized>(folder: &mut F, ast: &mut Ast, id: NodeId) -> NodeId {
+    match ast[id].clone() {
+        Tree::Nil => folder.fold_nil(ast, id),
+        Tree::Atom(atom) => folder.fold_atom(ast, id, atom),
+        Tree::Op(op) => folder.fold_op(ast, id, op),
+        Tree::ExprStatment(ids) => folder.fold_expr_statment(ast, id, ids),
+        Tree::Var(name, value) => folder.fold_var(ast, id, name, value),
+        Tree::Call { callee, arguments } => folder.fold_call(ast, id, callee, arguments),
+        Tree::NonTerm(op, ids) => folder.fold_non_term(ast, id, op, ids),
+        Tree::Fun {
+            name,
+            parameters,
+            body,
+        } => folder.fold_fun(ast, id, name, parameters, body),
+        Tree::Lambda { parameters, body } => folder.fold_lambda(ast, id, parameters, body),
+    }
+}
+
+pub fn fold_ids<F: Folder + ?Sized>(
+    folder: &mut F,
+    ast: &mut Ast,
+    ids: Vec<NodeId>,
+) -> Vec<NodeId> {
+    ids.into_iter().map(|x| folder.fold_tree(ast, x)).collect()
+}